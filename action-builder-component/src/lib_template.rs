@@ -10,20 +10,41 @@ wit_bindgen::generate!({
     world: "nn",
 });
 
-#[cfg(feature = "component_nn")]
+#[cfg(feature = "component_nn_blobs")]
+wit_bindgen::generate!({
+    path: "wit",
+    world: "nn-blobs",
+});
+
+#[cfg(any(feature = "component_nn", feature = "component_nn_blobs"))]
 use self::wasi::nn::{
-    graph::{Graph, GraphBuilder, load, ExecutionTarget, GraphEncoding},
+    graph::{Graph, GraphBuilder, load, load_by_name, ExecutionTarget, GraphEncoding},
     tensor::{Tensor, TensorData, TensorDimensions, TensorType},
 };
 
 
 
 struct MyWorld;
+#[cfg(not(feature = "component_nn_blobs"))]
+impl Guest for MyWorld {
+    fn func_wrapper(json_string: std::string::String) -> Result<std::string::String, std::string::String> {
+        // Errors are returned to the host, which reports them as application errors
+        let json: serde_json::Value = serde_json::from_str(&json_string).map_err(|err| err.to_string())?;
+        let result = func(json).map_err(|err| format!("{:#}", err))?;
+        Ok(result.to_string())
+    }
+}
+#[cfg(feature = "component_nn_blobs")]
 impl Guest for MyWorld {
-    fn func_wrapper(json_string: std::string::String) -> std::string::String {
-        let json: serde_json::Value = serde_json::from_str(&json_string).unwrap();
-        let result = func(json).unwrap();
-        result.to_string()
+    fn func_with_blobs(
+        json_string: std::string::String,
+        blobs: Vec<(std::string::String, Vec<u8>)>,
+    ) -> Result<std::string::String, std::string::String> {
+        // The function finds the blobs by the names the input gives them, such as `image/0`
+        let json: serde_json::Value = serde_json::from_str(&json_string).map_err(|err| err.to_string())?;
+        let blobs: std::collections::HashMap<_, _> = blobs.into_iter().collect();
+        let result = func(json, blobs).map_err(|err| format!("{:#}", err))?;
+        Ok(result.to_string())
     }
 }
 export!(MyWorld);
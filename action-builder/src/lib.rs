@@ -20,11 +20,17 @@ macro_rules! args_method {
 
         pub fn main() -> anyhow::Result<()> {
 
+            // argv[0] is the program name, and the input follows it
             let args: Vec<String> = std::env::args().collect();
-            let json_str = &args[0];
-            let json: serde_json::Value = serde_json::from_str(json_str)?;
+            let json = args
+                .get(1)
+                .ok_or_else(|| anyhow::anyhow!("No input was passed to the action"))
+                .and_then(|json_str| Ok(serde_json::from_str::<serde_json::Value>(json_str)?));
 
-            let result_json = $t(json)?;
+            // Failures are returned as an `error` field instead of trapping
+            let result_json = json
+                .and_then(|json| $t(json))
+                .unwrap_or_else(|err| serde_json::json!({ "error": format!("{:#}", err) }));
 
             unsafe {
                 RESULT = Some(result_json.to_string());
@@ -51,21 +57,23 @@ macro_rules! args_method {
 macro_rules! stdio_method {
     ($($t:ident)*) => ($(
 
-        use std::{error::Error, io::stdin};
+        use std::io::stdin;
 
 
         pub fn main() -> anyhow::Result<()> {
 
-            let json: serde_json::Value = serde_json::from_reader(stdin())?;
+            let json = serde_json::from_reader::<_, serde_json::Value>(stdin())
+                .map_err(anyhow::Error::from);
 
-            let result_json = $t(json)?;
+            // Build a response JSON such as: {"response": "value"}, which the runtime unwraps.
+            // Failures are returned as an `error` field instead of trapping
+            let result_json = match json.and_then(|json| $t(json)) {
+                Ok(result_json) => serde_json::json!({ "response": result_json }),
+                Err(err) => serde_json::json!({ "error": format!("{:#}", err) }),
+            };
 
-            // Build a response JSON such as: {"response": "value"}
-            let response_json = serde_json::json!({
-                "response": result_json
-            });
-
-            println!("{}", response_json.to_string());
+            // The result is the last line written to stdout
+            println!("{}", result_json.to_string());
             
             Ok(())
         }
@@ -109,15 +117,21 @@ macro_rules! memory_method {
             }
         }
 
+        fn parse_input(input: &[u8]) -> anyhow::Result<Value> {
+            let input_str = std::str::from_utf8(input)?;
+            Ok(serde_json::from_str(input_str)?)
+        }
+
         pub fn main() -> anyhow::Result<()> {
             unsafe {
                 // Parse the input JSON
                 let input_slice = std::slice::from_raw_parts(INPUT, INPUT_LEN);
-                let input_str = std::str::from_utf8(input_slice).unwrap();
-                let json: Value = serde_json::from_str(input_str).unwrap();
-        
-                // Call the function
-                let result_json = $t(json)?;
+                let json = parse_input(input_slice);
+
+                // Call the function, failures are returned as an `error` field instead of trapping
+                let result_json = json
+                    .and_then(|json| $t(json))
+                    .unwrap_or_else(|err| serde_json::json!({ "error": format!("{:#}", err) }));
         
                 // Save the result as a string
                 RESULT = Some(result_json.to_string());
@@ -152,6 +166,8 @@ macro_rules! memory_nn_method {
         static mut RESULT: Option<String> = None;
         static mut MODEL: *mut u8 = ptr::null_mut();
         static mut MODEL_LEN: usize = 0;
+        // The name and data of each blob, one after the other
+        static mut BLOBS: Vec<(*mut u8, usize, usize)> = Vec::new();
 
         #[no_mangle]
         pub extern "C" fn set_input(size: usize) -> *mut u8 {
@@ -185,17 +201,44 @@ macro_rules! memory_nn_method {
             }
         }
 
+        #[no_mangle]
+        pub extern "C" fn set_blob(name_len: usize, size: usize) -> *mut u8 {
+            unsafe {
+                let blob = alloc(Layout::from_size_align(name_len + size, 1).unwrap());
+                BLOBS.push((blob, name_len, size));
+                blob
+            }
+        }
+
+        /// The blob the host passed as `name`, such as `image/0` for the first image, which the
+        /// input names instead of carrying it base64-encoded.
+        pub fn blob(name: &str) -> Option<&'static [u8]> {
+            unsafe {
+                BLOBS.iter().find_map(|&(blob, name_len, size)| {
+                    let bytes = std::slice::from_raw_parts(blob, name_len + size);
+                    (&bytes[..name_len] == name.as_bytes()).then(|| &bytes[name_len..])
+                })
+            }
+        }
+
+        fn parse_input(input: &[u8]) -> anyhow::Result<Value> {
+            let input_str = std::str::from_utf8(input)?;
+            Ok(serde_json::from_str(input_str)?)
+        }
+
         pub fn main() -> anyhow::Result<()> {
             unsafe {
                 // Parse the input JSON
                 let input_slice = std::slice::from_raw_parts(INPUT, INPUT_LEN);
-                let input_str = std::str::from_utf8(input_slice).unwrap();
-                let json: Value = serde_json::from_str(input_str).unwrap();
+                let json = parse_input(input_slice);
 
-                let model_bytes = unsafe { std::slice::from_raw_parts(MODEL, MODEL_LEN) };
+                // Empty when the host does not pass the model, for actions that `load_by_name`
+                let model_bytes: &[u8] = if MODEL.is_null() { &[] } else { std::slice::from_raw_parts(MODEL, MODEL_LEN) };
         
-                // Call the function
-                let result_json = $t(json, model_bytes)?;
+                // Call the function, failures are returned as an `error` field instead of trapping
+                let result_json = json
+                    .and_then(|json| $t(json, model_bytes))
+                    .unwrap_or_else(|err| serde_json::json!({ "error": format!("{:#}", err) }));
         
                 // Save the result as a string
                 RESULT = Some(result_json.to_string());
@@ -231,6 +274,8 @@ macro_rules! memory_nn_parallel_method {
         static mut RESULT: Option<String> = None;
         static mut MODEL: *mut u8 = ptr::null_mut();
         static mut MODEL_LEN: usize = 0;
+        // The name and data of each blob, one after the other
+        static mut BLOBS: Vec<(*mut u8, usize, usize)> = Vec::new();
 
         #[no_mangle]
         pub extern "C" fn set_input(size: usize) -> *mut u8 {
@@ -264,18 +309,45 @@ macro_rules! memory_nn_parallel_method {
             }
         }
 
+        #[no_mangle]
+        pub extern "C" fn set_blob(name_len: usize, size: usize) -> *mut u8 {
+            unsafe {
+                let blob = alloc(Layout::from_size_align(name_len + size, 1).unwrap());
+                BLOBS.push((blob, name_len, size));
+                blob
+            }
+        }
+
+        /// The blob the host passed as `name`, such as `image/0` for the first image, which the
+        /// input names instead of carrying it base64-encoded.
+        pub fn blob(name: &str) -> Option<&'static [u8]> {
+            unsafe {
+                BLOBS.iter().find_map(|&(blob, name_len, size)| {
+                    let bytes = std::slice::from_raw_parts(blob, name_len + size);
+                    (&bytes[..name_len] == name.as_bytes()).then(|| &bytes[name_len..])
+                })
+            }
+        }
+
+        fn parse_input(input: &[u8]) -> anyhow::Result<Value> {
+            let input_str = std::str::from_utf8(input)?;
+            Ok(serde_json::from_str(input_str)?)
+        }
+
         pub fn main() -> anyhow::Result<()> {
             unsafe {
                 let instance_start = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
                 // Parse the input JSON
                 let input_slice = std::slice::from_raw_parts(INPUT, INPUT_LEN);
-                let input_str = std::str::from_utf8(input_slice).unwrap();
-                let json: Value = serde_json::from_str(input_str).unwrap();
+                let json = parse_input(input_slice);
 
-                let model_bytes = unsafe { std::slice::from_raw_parts(MODEL, MODEL_LEN) };
+                // Empty when the host does not pass the model, for actions that `load_by_name`
+                let model_bytes: &[u8] = if MODEL.is_null() { &[] } else { std::slice::from_raw_parts(MODEL, MODEL_LEN) };
 
-                // Call the function
-                let mut result_json = $t(json, model_bytes)?;
+                // Call the function, failures are returned as an `error` field instead of trapping
+                let mut result_json = json
+                    .and_then(|json| $t(json, model_bytes))
+                    .unwrap_or_else(|err| serde_json::json!({ "error": format!("{:#}", err) }));
                 let instance_end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
 
                 if let Some(result_obj) = result_json.as_object_mut() {
@@ -10,8 +10,17 @@ pub fn func(json: serde_json::Value, model_bytes: &[u8]) -> Result<serde_json::V
     let mut metrics: HashMap<String, f64> = HashMap::new();
 
     let start_time = Instant::now();
-    let graph = GraphBuilder::new(GraphEncoding::Pytorch, ExecutionTarget::CPU)
-        .build_from_bytes(&[&model_bytes])?;
+    // Actions with the `nn_load_by_name` annotation get the graph the host loaded for the
+    // 'model' URL, and no model bytes
+    let builder = GraphBuilder::new(GraphEncoding::Pytorch, ExecutionTarget::CPU);
+    let graph = if model_bytes.is_empty() {
+        let model = json["model"].as_str().ok_or_else(|| {
+            anyhow::anyhow!("From wasm: 'model' not found or not a string in JSON")
+        })?;
+        builder.build_from_cache(model)?
+    } else {
+        builder.build_from_bytes(&[&model_bytes])?
+    };
     metrics.insert("graph_build_time".to_string(), start_time.elapsed().as_secs_f64());
 
     let start_time = Instant::now();
@@ -117,12 +126,18 @@ fn preprocess(
     // Iterate over each image in the list
     for image_base64 in images_base64 {
         let image_base64_str = image_base64.as_str().ok_or_else(|| {
-            anyhow::anyhow!("From wasm: 'image' should be a base64 string")
+            anyhow::anyhow!("From wasm: 'image' should be a blob name or a base64 string")
         })?;
-        println!("Decoding image from base64...");
 
-        let image_bytes = base64::decode(image_base64_str)?;
-        println!("Image decoded, preprocessing...");
+        // Executors that pass images as blobs name them instead of encoding them
+        let image_bytes = match blob(image_base64_str) {
+            Some(image_bytes) => image_bytes.to_vec(),
+            None => {
+                println!("Decoding image from base64...");
+                base64::decode(image_base64_str)?
+            }
+        };
+        println!("Image loaded, preprocessing...");
 
         // Preprocess the image and add it to the batch
         let tensor_data = preprocess_one(image_bytes, width, height, mean, std);
@@ -5,13 +5,27 @@ set -e
 WASMTIME=${WASMTIME_PATH:-"/opt/wasmtime-v27.0.0-x86_64-linux/wasmtime"}
 export WASMTIME
 
+# The executor compiles plain .wasm files itself. Set PRECOMPILE=1 to upload a .cwasm file
+# precompiled by the wasmtime CLI instead, which requires OW_TRUST_PRECOMPILED=1 on the executor
+PRECOMPILE=${PRECOMPILE:-0}
+export PRECOMPILE
+
+# A .cwasm file only loads into an engine with the same settings. The executor always enables
+# epoch interruption, and fuel consumption when OW_FUEL_METERING is set (the amount of fuel
+# does not matter when compiling)
+COMPILE_FLAGS="-W epoch-interruption=y"
+case "${OW_FUEL_METERING,,}" in
+    1|y|yes|t|true|on) COMPILE_FLAGS="$COMPILE_FLAGS -W fuel=1" ;;
+esac
+export COMPILE_FLAGS
+
 
 # Supported methods
-INPUT_METHODS=("memory" "memory_nn" "component" "component_nn" "memory_nn_parallel")
+INPUT_METHODS=("args" "stdio" "memory" "memory_nn" "component" "component_nn" "component_nn_blobs" "memory_nn_parallel")
 
 
 # Check if the necessary arguments are passed
-# methods can be args, stdio, memory, or component
+# methods can be args, stdio, memory, memory_nn, memory_nn_parallel, component, component_nn or component_nn_blobs
 if [ "$#" -ne 2 ]; then
     echo "Usage: $0 <filename> <input_method>"
     echo "Supported argument passing methods: ${INPUT_METHODS[*]}"
@@ -29,13 +43,13 @@ if [[ ! " ${INPUT_METHODS[@]} " =~ " ${METHOD} " ]]; then
 fi
 
 # Check if the version matches the required version (27.0.0)
-if [ "$($WASMTIME --version)" != "wasmtime 27.0.0 (8eefa236f 2024-11-20)" ]; then
+if [ "$PRECOMPILE" == "1" ] && [ "$($WASMTIME --version)" != "wasmtime 27.0.0 (8eefa236f 2024-11-20)" ]; then
     echo "The version of wasmtime is not 27.0.0. Please install the correct version."
     exit 1
 fi
 
-# If the METHOD is component or component_nn, call compile_component.sh $INPUT_FILE
-if [ "$METHOD" == "component" ] || [ "$METHOD" == "component_nn" ]; then
+# If the METHOD is component, component_nn or component_nn_blobs, call compile_component.sh $INPUT_FILE
+if [ "$METHOD" == "component" ] || [ "$METHOD" == "component_nn" ] || [ "$METHOD" == "component_nn_blobs" ]; then
     ./actions/compile_component.sh "$INPUT_FILE" "$METHOD"
     exit 0
 fi
@@ -91,13 +105,26 @@ fi
 
 mkdir -p "actions/compiled"
 
-# Compile the WASM to a .cwasm file
-$WASMTIME compile "target/wasm32-wasip1/release/examples/$FILENAME.wasm" -o "./actions/compiled/$FILENAME.cwasm"
+if [ "$PRECOMPILE" == "1" ]; then
+    # Compile the WASM to a .cwasm file
+    $WASMTIME compile $COMPILE_FLAGS "target/wasm32-wasip1/release/examples/$FILENAME.wasm" -o "./actions/compiled/$FILENAME.cwasm"
+    ARTIFACT="./actions/compiled/$FILENAME.cwasm"
+else
+    cp "target/wasm32-wasip1/release/examples/$FILENAME.wasm" "./actions/compiled/$FILENAME.wasm"
+    ARTIFACT="./actions/compiled/$FILENAME.wasm"
+fi
 
-# Package the .cwasm file into a zip
-zip "./actions/compiled/$FILENAME.zip" "./actions/compiled/$FILENAME.cwasm"
+# Package the artifact into a zip
+rm -f "./actions/compiled/$FILENAME.zip"
+zip "./actions/compiled/$FILENAME.zip" "$ARTIFACT"
+
+# The args and stdio methods need the runtime to pass the input the same way
+ANNOTATIONS=()
+if [ "$METHOD" == "args" ] || [ "$METHOD" == "stdio" ]; then
+    ANNOTATIONS=(--annotation abi "$METHOD")
+fi
 
 # Deploy to OpenWhisk
-wsk action update --kind wasm:0.1 "$FILENAME" "./actions/compiled/$FILENAME.zip"
+wsk action update --kind wasm:0.1 "$FILENAME" "./actions/compiled/$FILENAME.zip" "${ANNOTATIONS[@]}"
 
 echo "Action '$FILENAME' updated with '$METHOD' argument passing method."
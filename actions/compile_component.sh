@@ -45,11 +45,18 @@ fi
 
 mkdir -p "actions/compiled"
 
-# Compile the WASM to a .cwasm file
-$WASMTIME compile "target/wasm32-wasip2/release/action_component.wasm" -o "./actions/compiled/$FILENAME.cwasm"
+if [ "$PRECOMPILE" == "1" ]; then
+    # Compile the WASM to a .cwasm file
+    $WASMTIME compile $COMPILE_FLAGS "target/wasm32-wasip2/release/action_component.wasm" -o "./actions/compiled/$FILENAME.cwasm"
+    ARTIFACT="./actions/compiled/$FILENAME.cwasm"
+else
+    cp "target/wasm32-wasip2/release/action_component.wasm" "./actions/compiled/$FILENAME.wasm"
+    ARTIFACT="./actions/compiled/$FILENAME.wasm"
+fi
 
-# Package the .cwasm file into a zip
-zip "./actions/compiled/$FILENAME.zip" "./actions/compiled/$FILENAME.cwasm"
+# Package the artifact into a zip
+rm -f "./actions/compiled/$FILENAME.zip"
+zip "./actions/compiled/$FILENAME.zip" "$ARTIFACT"
 
 # Deploy to OpenWhisk
 wsk action update --kind wasm:0.1 "$FILENAME" "./actions/compiled/$FILENAME.zip"
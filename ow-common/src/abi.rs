@@ -0,0 +1,928 @@
+use crate::logs::GuestLogs;
+use crate::{util, ActionCapabilities, RuntimeError};
+use bytes::Bytes;
+use serde::de::IgnoredAny;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::ops::Range;
+use tracing::info_span;
+use wasmtime::component::{Component, Val};
+use wasmtime::{Engine, Instance, Module, Store};
+use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::{I32Exit, WasiCtxBuilder};
+
+/// How many bytes a guest of the stdio ABI may write to stdout, result included.
+pub const STDOUT_LIMIT: usize = 64 * 1024 * 1024;
+/// The program name guests of the args ABI find in `argv[0]`, before their input.
+pub const PROGRAM_NAME: &str = "action";
+/// The export of components of the `nn-blobs` world, which take blobs next to the input.
+pub const BLOBS_EXPORT: &str = "func-with-blobs";
+
+/// What a guest returned: `Ok` on success, or `Err` for an application error.
+pub type GuestResult = Result<Value, Value>;
+
+/// The calling conventions an action can be built with, chosen by its `abi` annotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AbiKind {
+    /// The input is written to the buffer returned by `set_input`, and the result is read from
+    /// `get_result` and `get_result_len`, as built by `memory_method!`.
+    Memory,
+    /// The input is the first argument of the guest, in `argv[1]`. The result is read from
+    /// `get_result` and `get_result_len` as built by `args_method!`, or else from stdout.
+    Args,
+    /// The input is written to stdin, and the result is what the guest writes to stdout, as
+    /// built by `stdio_method!` or by any WASI command.
+    Stdio,
+    /// The input is passed to the `func-wrapper` export of a component, which returns the result.
+    Component,
+}
+
+impl AbiKind {
+    pub const ALL: [AbiKind; 4] = [AbiKind::Memory, AbiKind::Args, AbiKind::Stdio, AbiKind::Component];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AbiKind::Memory => "memory",
+            AbiKind::Args => "args",
+            AbiKind::Stdio => "stdio",
+            AbiKind::Component => "component",
+        }
+    }
+
+    pub fn is_component(&self) -> bool {
+        matches!(self, AbiKind::Component)
+    }
+
+    /// The ABI of an action, from its `abi` annotation or else from its kind of artifact:
+    /// `memory` for modules and `component` for components.
+    pub fn of(capabilities: &ActionCapabilities, component: bool) -> anyhow::Result<AbiKind> {
+        let kind = match &capabilities.abi {
+            Some(name) => name.parse()?,
+            None if component => AbiKind::Component,
+            None => AbiKind::Memory,
+        };
+
+        if kind.is_component() != component {
+            return Err(RuntimeError::developer(format!(
+                "The {} ABI does not apply to a {}",
+                kind,
+                if component { "component" } else { "module" }
+            ))
+            .into());
+        }
+
+        Ok(kind)
+    }
+
+    /// The implementation of this ABI for stores of `T`.
+    pub fn abi<T: 'static>(self) -> &'static dyn GuestAbi<T> {
+        match self {
+            AbiKind::Memory => &MemoryAbi,
+            AbiKind::Args => &ArgsAbi,
+            AbiKind::Stdio => &StdioAbi,
+            AbiKind::Component => &ComponentAbi,
+        }
+    }
+}
+
+impl std::fmt::Display for AbiKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for AbiKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| RuntimeError::developer(format!("Unknown ABI {}", s)).into())
+    }
+}
+
+/// Error raised when a guest hands the host a buffer that is not inside its memory, so that a
+/// buggy or malicious module fails its activation instead of making the host panic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutOfBounds {
+    /// The export that returned the pointer to the buffer.
+    pub export: &'static str,
+    pub ptr: usize,
+    pub len: usize,
+    /// The size of the memory of the guest, in bytes.
+    pub memory_size: usize,
+}
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} returned a buffer of {} bytes at {}, outside of the {} bytes of memory of the action.",
+            self.export, self.len, self.ptr, self.memory_size
+        )
+    }
+}
+
+impl std::error::Error for OutOfBounds {}
+
+/// An instance of an action, as called by its ABI.
+#[derive(Clone, Copy)]
+pub enum Guest<'a> {
+    Module(&'a Instance),
+    Component(&'a wasmtime::component::Instance),
+}
+
+impl<'a> Guest<'a> {
+    fn module(self) -> anyhow::Result<&'a Instance> {
+        match self {
+            Guest::Module(instance) => Ok(instance),
+            Guest::Component(_) => Err(RuntimeError::developer("The action is not a module").into()),
+        }
+    }
+
+    fn component(self) -> anyhow::Result<&'a wasmtime::component::Instance> {
+        match self {
+            Guest::Component(instance) => Ok(instance),
+            Guest::Module(_) => Err(RuntimeError::developer("The action is not a component").into()),
+        }
+    }
+}
+
+/// The arguments and stdio of the store of one activation, as its ABI wants them.
+pub struct GuestIo {
+    logs: GuestLogs,
+    args: Vec<String>,
+    stdin: Option<Bytes>,
+    stdout: Option<MemoryOutputPipe>,
+    blobs: Vec<Blob>,
+}
+
+impl GuestIo {
+    /// No arguments, and stdout and stderr written to `logs`.
+    pub fn new(logs: &GuestLogs) -> Self {
+        Self {
+            logs: logs.clone(),
+            args: Vec::new(),
+            stdin: None,
+            stdout: None,
+            blobs: Vec::new(),
+        }
+    }
+
+    /// Passes `blobs` to the guest next to its input, if it takes blobs.
+    pub fn set_blobs(&mut self, blobs: Vec<Blob>) {
+        self.blobs = blobs;
+    }
+
+    /// Sets up the WASI context of the store with these arguments and stdio.
+    pub fn attach(&self, builder: &mut WasiCtxBuilder) {
+        builder.args(&self.args);
+        if let Some(stdin) = &self.stdin {
+            builder.stdin(MemoryInputPipe::new(stdin.clone()));
+        }
+        match &self.stdout {
+            Some(stdout) => {
+                builder.stdout(stdout.clone());
+                self.logs.attach_stderr(builder);
+            }
+            None => self.logs.attach(builder),
+        }
+    }
+
+    /// What the guest wrote to stdout, if it was captured.
+    fn stdout(&self) -> Bytes {
+        self.stdout.as_ref().map(MemoryOutputPipe::contents).unwrap_or_default()
+    }
+}
+
+/// A named binary input of a guest, such as a fetched image, that is written to its memory as
+/// it is instead of base64-encoded inside the JSON input.
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub name: String,
+    pub data: Bytes,
+}
+
+/// Collects the binary inputs fetched for an activation, such as its images. Guests that take
+/// blobs get each of them raw, and find its name in their input; the others find it
+/// base64-encoded in their input.
+#[derive(Debug, Default)]
+pub struct Blobs {
+    takes_blobs: bool,
+    blobs: Vec<Blob>,
+    images: usize,
+}
+
+impl Blobs {
+    pub fn new(takes_blobs: bool) -> Self {
+        Self {
+            takes_blobs,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a blob named `name`, and returns what stands for it in the input of the guest.
+    pub fn blob(&mut self, name: &str, data: impl Into<Bytes>) -> Value {
+        let data = data.into();
+        if !self.takes_blobs {
+            return Value::String(base64::encode(&data));
+        }
+
+        self.blobs.push(Blob {
+            name: name.to_owned(),
+            data,
+        });
+        Value::String(name.to_owned())
+    }
+
+    /// Adds the next image, as a blob named `image/<index>`.
+    pub fn image(&mut self, data: impl Into<Bytes>) -> Value {
+        let name = format!("image/{}", self.images);
+        self.images += 1;
+        self.blob(&name, data)
+    }
+
+    pub fn into_vec(self) -> Vec<Blob> {
+        self.blobs
+    }
+}
+
+/// Whether a module takes blobs, by exporting `set_blob` as built by `memory_nn_method!` and
+/// `memory_nn_parallel_method!`. Only the memory ABI passes them.
+pub fn module_takes_blobs(abi: AbiKind, module: &Module) -> bool {
+    abi == AbiKind::Memory && module.get_export("set_blob").is_some()
+}
+
+/// Whether a component takes blobs, by exporting `func-with-blobs` of the `nn-blobs` world.
+pub fn component_takes_blobs(engine: &Engine, component: &Component) -> bool {
+    component
+        .component_type()
+        .exports(engine)
+        .any(|(name, _)| name == BLOBS_EXPORT)
+}
+
+/// A calling convention between the runtimes and their guests: how the input of an activation
+/// gets into the guest, and how its result comes back.
+pub trait GuestAbi<T>: Send + Sync {
+    /// The arguments and stdio of a store that runs `input`. By default the guest gets no
+    /// arguments, and its stdout and stderr go to `logs`.
+    fn io(&self, _input: &Value, logs: &GuestLogs) -> GuestIo {
+        GuestIo::new(logs)
+    }
+
+    /// Passes `input` to `guest`, runs it and reads its result back. `io` is what `io` returned
+    /// for the store.
+    fn invoke(
+        &self,
+        guest: Guest<'_>,
+        store: &mut Store<T>,
+        input: &Value,
+        io: &GuestIo,
+    ) -> anyhow::Result<GuestResult>;
+}
+
+pub struct MemoryAbi;
+
+impl<T> GuestAbi<T> for MemoryAbi {
+    fn invoke(
+        &self,
+        guest: Guest<'_>,
+        store: &mut Store<T>,
+        input: &Value,
+        io: &GuestIo,
+    ) -> anyhow::Result<GuestResult> {
+        let instance = guest.module()?;
+
+        pass_input(instance, store, input)?;
+        for blob in &io.blobs {
+            pass_blob(instance, store, blob)?;
+        }
+        call_start(instance, store)?;
+
+        Ok(util::application_result(retrieve_result(instance, store)?))
+    }
+}
+
+pub struct ArgsAbi;
+
+impl<T> GuestAbi<T> for ArgsAbi {
+    fn io(&self, input: &Value, logs: &GuestLogs) -> GuestIo {
+        GuestIo {
+            args: vec![PROGRAM_NAME.to_owned(), input.to_string()],
+            stdout: Some(MemoryOutputPipe::new(STDOUT_LIMIT)),
+            ..GuestIo::new(logs)
+        }
+    }
+
+    fn invoke(
+        &self,
+        guest: Guest<'_>,
+        store: &mut Store<T>,
+        _input: &Value,
+        io: &GuestIo,
+    ) -> anyhow::Result<GuestResult> {
+        let instance = guest.module()?;
+
+        let status = call_start(instance, store);
+        // Commands that were not built with `args_method!` print their result instead
+        if status.is_err() || instance.get_export(&mut *store, "get_result_len").is_none() {
+            return Ok(util::application_result(stdout_result(io, status)?));
+        }
+        io.logs.log_stdout(&io.stdout());
+
+        Ok(util::application_result(retrieve_result(instance, store)?))
+    }
+}
+
+pub struct StdioAbi;
+
+impl<T> GuestAbi<T> for StdioAbi {
+    fn io(&self, input: &Value, logs: &GuestLogs) -> GuestIo {
+        GuestIo {
+            stdin: Some(Bytes::from(input.to_string())),
+            stdout: Some(MemoryOutputPipe::new(STDOUT_LIMIT)),
+            ..GuestIo::new(logs)
+        }
+    }
+
+    fn invoke(
+        &self,
+        guest: Guest<'_>,
+        store: &mut Store<T>,
+        _input: &Value,
+        io: &GuestIo,
+    ) -> anyhow::Result<GuestResult> {
+        let instance = guest.module()?;
+
+        let status = call_start(instance, store);
+
+        Ok(util::application_result(unwrap_response(stdout_result(io, status)?)))
+    }
+}
+
+pub struct ComponentAbi;
+
+impl<T> GuestAbi<T> for ComponentAbi {
+    fn invoke(
+        &self,
+        guest: Guest<'_>,
+        store: &mut Store<T>,
+        input: &Value,
+        io: &GuestIo,
+    ) -> anyhow::Result<GuestResult> {
+        let instance = guest.component()?;
+
+        let input = serde_json::to_string(input)?;
+
+        // Components of the `nn-blobs` world take the blobs as `list<u8>` next to the input
+        if let Some(func) = instance.get_func(&mut *store, BLOBS_EXPORT) {
+            let func = func
+                .typed::<(&str, &[(&str, &[u8])]), (Result<String, String>,)>(&*store)
+                .map_err(|err| RuntimeError::developer(format!("{} has the wrong type: {}", BLOBS_EXPORT, err)))?;
+            let blobs: Vec<(&str, &[u8])> = io
+                .blobs
+                .iter()
+                .map(|blob| (blob.name.as_str(), &blob.data[..]))
+                .collect();
+            let (output,) = info_span!("guest_execution")
+                .in_scope(|| func.call(&mut *store, (input.as_str(), blobs.as_slice())))?;
+
+            let output = match output {
+                Ok(result) => Val::Result(Ok(Some(Box::new(Val::String(result))))),
+                Err(error) => Val::Result(Err(Some(Box::new(Val::String(error))))),
+            };
+            return component_result(&output);
+        }
+        if !io.blobs.is_empty() {
+            return Err(RuntimeError::developer(format!("Failed to get {}", BLOBS_EXPORT)).into());
+        }
+
+        let mut output = [Val::String("".into())];
+
+        let func = instance
+            .get_func(&mut *store, "func-wrapper")
+            .ok_or_else(|| RuntimeError::developer("Failed to get func-wrapper"))?;
+        info_span!("guest_execution")
+            .in_scope(|| func.call(&mut *store, &[Val::String(input)], &mut output))?;
+
+        component_result(&output[0])
+    }
+}
+
+/// Writes a blob to the buffer returned by the `set_blob` export of a module, which is given the
+/// length of the name and of the data of the blob and takes the name first.
+#[tracing::instrument(skip_all, fields(blob = %blob.name))]
+fn pass_blob<T>(instance: &Instance, store: &mut Store<T>, blob: &Blob) -> anyhow::Result<()> {
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| RuntimeError::developer("Failed to get WASM memory"))?;
+    let set_blob = instance
+        .get_typed_func::<(u32, u32), u32>(&mut *store, "set_blob")
+        .map_err(|_| RuntimeError::developer("Failed to get set_blob"))?;
+
+    let name = blob.name.as_bytes();
+    let len = name.len() + blob.data.len();
+    if u32::try_from(len).is_err() {
+        return Err(RuntimeError::developer(format!("{} bytes do not fit in the memory of the action", len)).into());
+    }
+    let ptr = set_blob.call(&mut *store, (name.len() as u32, blob.data.len() as u32))? as usize;
+
+    let data = memory.data_mut(&mut *store);
+    let range = guest_range("set_blob", ptr, len, data.len())?;
+    let (name_buffer, data_buffer) = data[range].split_at_mut(name.len());
+    name_buffer.copy_from_slice(name);
+    data_buffer.copy_from_slice(&blob.data);
+
+    Ok(())
+}
+
+/// Writes the model of a wasi-nn action to the buffer returned by its `set_model` export, as
+/// built by `memory_nn_method!` and `memory_nn_parallel_method!`.
+#[tracing::instrument(skip_all)]
+pub fn pass_model<T>(instance: &Instance, store: &mut Store<T>, model: &[u8]) -> anyhow::Result<()> {
+    write_buffer(instance, store, "set_model", model)
+}
+
+#[tracing::instrument(skip_all)]
+fn pass_input<T>(instance: &Instance, store: &mut Store<T>, input: &Value) -> anyhow::Result<()> {
+    write_buffer(instance, store, "set_input", input.to_string().as_bytes())
+}
+
+/// Asks the guest for a buffer of the size of `bytes` with `setter`, and writes them to it.
+fn write_buffer<T>(
+    instance: &Instance,
+    store: &mut Store<T>,
+    setter: &'static str,
+    bytes: &[u8],
+) -> anyhow::Result<()> {
+    // Access the WASM memory
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| RuntimeError::developer("Failed to get WASM memory"))?;
+
+    // Obtain the pointer to the buffer
+    let setter_func = instance
+        .get_typed_func::<u32, u32>(&mut *store, setter)
+        .map_err(|_| RuntimeError::developer(format!("Failed to get {}", setter)))?;
+    let len = u32::try_from(bytes.len()).map_err(|_| {
+        RuntimeError::developer(format!("{} bytes do not fit in the memory of the action", bytes.len()))
+    })?;
+    let ptr = setter_func.call(&mut *store, len)? as usize;
+
+    // Write the bytes to the WASM memory, where the guest said it has room for them
+    let data = memory.data_mut(&mut *store);
+    let range = guest_range(setter, ptr, bytes.len(), data.len())?;
+    data[range].copy_from_slice(bytes);
+
+    Ok(())
+}
+
+/// Runs the `_start` function of a module. A guest that exits with `proc_exit(0)`, as WASI
+/// commands written in C or Go do, succeeded; any other status is a developer error.
+fn call_start<T>(instance: &Instance, store: &mut Store<T>) -> anyhow::Result<()> {
+    let main = instance
+        .get_typed_func::<(), ()>(&mut *store, "_start")
+        .map_err(|_| RuntimeError::developer("Failed to get _start"))?;
+
+    match info_span!("guest_execution").in_scope(|| main.call(&mut *store, ())) {
+        Ok(()) => Ok(()),
+        Err(err) => match err.downcast_ref::<I32Exit>() {
+            Some(I32Exit(0)) => Ok(()),
+            Some(I32Exit(status)) => {
+                Err(RuntimeError::developer(format!("The action exited with status {}", status)).into())
+            }
+            None => Err(err),
+        },
+    }
+}
+
+/// Reads the result of a guest from what it wrote to stdout once `_start` returned `status`.
+/// Everything else it wrote is logged, including the whole output of a guest that failed.
+fn stdout_result(io: &GuestIo, status: anyhow::Result<()>) -> anyhow::Result<Value> {
+    let stdout = io.stdout();
+    if let Err(err) = status {
+        io.logs.log_stdout(&stdout);
+        return Err(err);
+    }
+
+    let (output, result) = split_result(&stdout)
+        .ok_or_else(|| RuntimeError::developer("The action wrote no result to stdout"))?;
+    if !output.is_empty() {
+        io.logs.log_stdout(output);
+    }
+
+    Ok(parse_result(result)?)
+}
+
+/// Takes the result out of the `{"response": ...}` object that `stdio_method!` wraps it in.
+/// Any other result is returned as is. Only stdio guests wrap their results.
+fn unwrap_response(mut result: Value) -> Value {
+    if let Value::Object(object) = &mut result {
+        if object.len() == 1 {
+            if let Some(response) = object.remove("response") {
+                return response;
+            }
+        }
+    }
+    result
+}
+
+/// Reads the result that a module exposes with `get_result` and `get_result_len`.
+#[tracing::instrument(skip_all)]
+fn retrieve_result<T>(instance: &Instance, store: &mut Store<T>) -> anyhow::Result<Value> {
+    // Access the WASM memory
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| RuntimeError::developer("Failed to get WASM memory"))?;
+
+    // Obtain the length of the result with get_result_len
+    let get_result_len = instance
+        .get_typed_func::<(), u32>(&mut *store, "get_result_len")
+        .map_err(|_| RuntimeError::developer("Failed to get get_result_len"))?;
+    let length = get_result_len.call(&mut *store, ())? as usize;
+
+    // Obtain the pointer to the result with get_result
+    let get_result = instance
+        .get_typed_func::<(), u32>(&mut *store, "get_result")
+        .map_err(|_| RuntimeError::developer("Failed to get get_result"))?;
+    let content_ptr = get_result.call(&mut *store, ())? as usize;
+
+    // Read the result from the WASM memory
+    let data = memory.data(&*store);
+    let range = guest_range("get_result", content_ptr, length, data.len())?;
+
+    Ok(parse_result(&data[range])?)
+}
+
+/// The `len` bytes at `ptr` that a guest handed to the host through `export`, if they are
+/// inside its memory of `memory_size` bytes.
+fn guest_range(
+    export: &'static str,
+    ptr: usize,
+    len: usize,
+    memory_size: usize,
+) -> Result<Range<usize>, OutOfBounds> {
+    match ptr.checked_add(len) {
+        Some(end) if end <= memory_size => Ok(ptr..end),
+        _ => Err(OutOfBounds {
+            export,
+            ptr,
+            len,
+            memory_size,
+        }),
+    }
+}
+
+/// Splits what a guest wrote to stdout into what it printed and its result: the last line, or
+/// all of it if it is a single JSON value.
+fn split_result(stdout: &[u8]) -> Option<(&[u8], &[u8])> {
+    let stdout = stdout.trim_ascii_end();
+    if stdout.is_empty() {
+        return None;
+    }
+    // A result printed over several lines, such as pretty-printed JSON, is taken whole
+    if serde_json::from_slice::<IgnoredAny>(stdout).is_ok() {
+        return Some((&[], stdout));
+    }
+
+    match stdout.iter().rposition(|byte| *byte == b'\n') {
+        Some(newline) => Some((&stdout[..=newline], &stdout[newline + 1..])),
+        None => Some((&[], stdout)),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+fn parse_result(result: &[u8]) -> Result<Value, RuntimeError> {
+    let result = std::str::from_utf8(result)
+        .map_err(|err| RuntimeError::developer(format!("The action result is not valid UTF-8: {}", err)))?;
+
+    serde_json::from_str(result)
+        .map_err(|err| RuntimeError::developer(format!("The action result is not valid JSON: {}", err)))
+}
+
+/// Reads the value returned by `func-wrapper`, which is either a JSON string or a
+/// `result<string, string>`.
+fn component_result(output: &Val) -> anyhow::Result<GuestResult> {
+    let failed = || serde_json::json!({ "error": "The action failed." });
+    let unexpected = |what: &str, value: &Val| {
+        RuntimeError::developer(format!(
+            "The action returned {} of type {}, expected a string",
+            what,
+            val_type(value)
+        ))
+    };
+
+    Ok(match output {
+        // Components built for `-> string` report application errors with an `error` field
+        Val::String(s) => util::application_result(parse_result(s.as_bytes())?),
+        Val::Result(Ok(Some(value))) => match value.as_ref() {
+            Val::String(s) => Ok(parse_result(s.as_bytes())?),
+            value => return Err(unexpected("a result", value).into()),
+        },
+        Val::Result(Ok(None)) => {
+            return Err(RuntimeError::developer("The action returned a result without a value").into())
+        }
+        Val::Result(Err(Some(value))) => match value.as_ref() {
+            // The error is usually a message, but JSON objects are passed through as they are
+            Val::String(s) => match serde_json::from_str(s) {
+                Ok(Value::Object(error)) => Err(Value::Object(error)),
+                _ => Err(serde_json::json!({ "error": s })),
+            },
+            _ => Err(failed()),
+        },
+        Val::Result(Err(None)) => Err(failed()),
+        value => return Err(unexpected("a value", value).into()),
+    })
+}
+
+/// The WIT name of the type of `value`, for errors about values of the wrong type.
+fn val_type(value: &Val) -> &'static str {
+    match value {
+        Val::Bool(_) => "bool",
+        Val::S8(_) => "s8",
+        Val::U8(_) => "u8",
+        Val::S16(_) => "s16",
+        Val::U16(_) => "u16",
+        Val::S32(_) => "s32",
+        Val::U32(_) => "u32",
+        Val::S64(_) => "s64",
+        Val::U64(_) => "u64",
+        Val::Float32(_) => "f32",
+        Val::Float64(_) => "f64",
+        Val::Char(_) => "char",
+        Val::String(_) => "string",
+        Val::List(_) => "list",
+        Val::Record(_) => "record",
+        Val::Tuple(_) => "tuple",
+        Val::Variant(..) => "variant",
+        Val::Enum(_) => "enum",
+        Val::Option(_) => "option",
+        Val::Result(_) => "result",
+        Val::Flags(_) => "flags",
+        Val::Resource(_) => "resource",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Linker;
+    use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+
+    // Echoes its input back as its result, like an action built with `memory_method!`
+    const ECHO: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $len (mut i32) (i32.const 0))
+            (func (export "set_input") (param i32) (result i32)
+                (global.set $len (local.get 0))
+                (i32.const 1024))
+            (func (export "_start"))
+            (func (export "get_result") (result i32) (i32.const 1024))
+            (func (export "get_result_len") (result i32) (global.get $len)))
+    "#;
+
+    // Returns the data of the blob it was given as its result
+    const BLOB_ECHO: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $name_len (mut i32) (i32.const 0))
+            (global $data_len (mut i32) (i32.const 0))
+            (func (export "set_input") (param i32) (result i32) (i32.const 4096))
+            (func (export "set_blob") (param i32 i32) (result i32)
+                (global.set $name_len (local.get 0))
+                (global.set $data_len (local.get 1))
+                (i32.const 1024))
+            (func (export "_start"))
+            (func (export "get_result") (result i32)
+                (i32.add (i32.const 1024) (global.get $name_len)))
+            (func (export "get_result_len") (result i32) (global.get $data_len)))
+    "#;
+
+    // Hands out pointers past the end of its single page of memory
+    const OUT_OF_BOUNDS: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "set_input") (param i32) (result i32) (i32.const 65530))
+            (func (export "_start"))
+            (func (export "get_result") (result i32) (i32.const -16))
+            (func (export "get_result_len") (result i32) (i32.const 32)))
+    "#;
+
+    // Prints a line, echoes stdin to stdout and exits with `$status`, like a WASI command
+    const STDIO_ECHO: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            ;; An iovec for the input at 1024, and one for the line at 512
+            (data (i32.const 0) "\00\04\00\00\00\10\00\00")
+            (data (i32.const 8) "\00\02\00\00\06\00\00\00")
+            (data (i32.const 512) "hello\n")
+            (func (export "_start")
+                (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 20)))
+                (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 16)))
+                (i32.store (i32.const 4) (i32.load (i32.const 16)))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20)))
+                (call $proc_exit (i32.const $status))))
+    "#;
+
+    fn run_stdio(status: i32, input: Value) -> (anyhow::Result<GuestResult>, Vec<String>) {
+        run_echo(AbiKind::Stdio, status, input)
+    }
+
+    // Runs `STDIO_ECHO` with the given ABI, always passing the input on stdin as well
+    fn run_echo(kind: AbiKind, status: i32, input: Value) -> (anyhow::Result<GuestResult>, Vec<String>) {
+        let engine = Engine::default();
+        let wat = STDIO_ECHO.replace("$status", &status.to_string());
+        let module = Module::new(&engine, wat).unwrap();
+        let mut linker = Linker::new(&engine);
+        preview1::add_to_linker_sync(&mut linker, |ctx: &mut WasiP1Ctx| ctx).unwrap();
+
+        let abi = kind.abi::<WasiP1Ctx>();
+        let logs = GuestLogs::new(&Default::default());
+        let io = GuestIo {
+            stdin: Some(Bytes::from(input.to_string())),
+            ..abi.io(&input, &logs)
+        };
+        let mut builder = WasiCtxBuilder::new();
+        io.attach(&mut builder);
+        let mut store = Store::new(&engine, builder.build_p1());
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+
+        let result = abi.invoke(Guest::Module(&instance), &mut store, &input, &io);
+        (result, logs.finish("activation"))
+    }
+
+    fn run(input: Value) -> GuestResult {
+        let engine = Engine::default();
+        let module = Module::new(&engine, ECHO).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let abi = AbiKind::Memory.abi::<()>();
+        let logs = GuestLogs::new(&Default::default());
+        let io = abi.io(&input, &logs);
+
+        abi.invoke(Guest::Module(&instance), &mut store, &input, &io).unwrap()
+    }
+
+    #[test]
+    fn memory_abi_passes_input_and_reads_the_result() {
+        let input = serde_json::json!({ "n": 42 });
+
+        assert_eq!(run(input.clone()), Ok(input));
+    }
+
+    #[test]
+    fn memory_abi_reports_application_errors() {
+        let input = serde_json::json!({ "error": "bad input" });
+
+        assert_eq!(run(input.clone()), Err(input));
+    }
+
+    #[test]
+    fn memory_abi_writes_blobs_next_to_the_input() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, BLOB_ECHO).unwrap();
+        assert!(module_takes_blobs(AbiKind::Memory, &module));
+        assert!(!module_takes_blobs(AbiKind::Args, &module));
+
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let abi = AbiKind::Memory.abi::<()>();
+        let input = serde_json::json!({ "image": ["image/0"] });
+        let mut io = abi.io(&input, &GuestLogs::new(&Default::default()));
+        io.set_blobs(vec![Blob {
+            name: "image/0".to_owned(),
+            data: Bytes::from_static(b"[1,2,3]"),
+        }]);
+
+        let result = abi.invoke(Guest::Module(&instance), &mut store, &input, &io).unwrap();
+        assert_eq!(result, Ok(serde_json::json!([1, 2, 3])));
+    }
+
+    #[test]
+    fn images_are_blobs_only_for_guests_that_take_them() {
+        let mut blobs = Blobs::new(true);
+        assert_eq!(blobs.image(&b"png"[..]), "image/0");
+        assert_eq!(blobs.image(&b"jpg"[..]), "image/1");
+        assert_eq!(blobs.into_vec()[1].data, &b"jpg"[..]);
+
+        let mut encoded = Blobs::new(false);
+        assert_eq!(encoded.image(&b"png"[..]), "cG5n");
+        assert!(encoded.into_vec().is_empty());
+    }
+
+    #[test]
+    fn buffers_outside_of_the_guest_memory_are_rejected() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, OUT_OF_BOUNDS).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+
+        let err = pass_input(&instance, &mut store, &serde_json::json!({ "n": 42 })).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<OutOfBounds>(),
+            Some(&OutOfBounds {
+                export: "set_input",
+                ptr: 65530,
+                len: 8,
+                memory_size: 65536
+            })
+        );
+
+        let err = RuntimeError::from(retrieve_result(&instance, &mut store).unwrap_err());
+        assert!(matches!(err, RuntimeError::ActionDeveloper(_)));
+    }
+
+    #[test]
+    fn stdio_abi_reads_the_result_from_stdout() {
+        let input = serde_json::json!({ "n": 42 });
+        let (result, logs) = run_stdio(0, input.clone());
+
+        assert_eq!(result.unwrap(), Ok(input));
+        assert!(logs.iter().any(|line| line.contains("hello")));
+    }
+
+    #[test]
+    fn stdio_abi_unwraps_the_response_of_stdio_method() {
+        let (result, _) = run_stdio(0, serde_json::json!({ "response": { "n": 42 } }));
+        assert_eq!(result.unwrap(), Ok(serde_json::json!({ "n": 42 })));
+
+        // Objects with other fields are not wrapped results
+        let input = serde_json::json!({ "response": 1, "status": 200 });
+        let (result, _) = run_stdio(0, input.clone());
+        assert_eq!(result.unwrap(), Ok(input));
+    }
+
+    #[test]
+    fn test_unexpected_component_results_are_developer_errors() {
+        let string = |s: &str| Some(Box::new(Val::String(s.to_owned())));
+        assert_eq!(
+            component_result(&Val::Result(Ok(string("{\"n\":42}")))).unwrap(),
+            Ok(serde_json::json!({ "n": 42 }))
+        );
+
+        let err = RuntimeError::from(component_result(&Val::U32(7)).unwrap_err());
+        assert!(matches!(err, RuntimeError::ActionDeveloper(_)));
+        assert_eq!(err.to_string(), "The action returned a value of type u32, expected a string");
+
+        let list = Val::Result(Ok(Some(Box::new(Val::List(Vec::new())))));
+        let err = RuntimeError::from(component_result(&list).unwrap_err());
+        assert!(matches!(err, RuntimeError::ActionDeveloper(_)));
+        assert_eq!(err.to_string(), "The action returned a result of type list, expected a string");
+
+        let err = RuntimeError::from(component_result(&Val::Result(Ok(None))).unwrap_err());
+        assert!(matches!(err, RuntimeError::ActionDeveloper(_)));
+    }
+
+    #[test]
+    fn test_only_the_stdio_abi_unwraps_responses() {
+        let input = serde_json::json!({ "response": { "n": 42 } });
+        let (result, _) = run_echo(AbiKind::Args, 0, input.clone());
+
+        assert_eq!(result.unwrap(), Ok(input));
+    }
+
+    #[test]
+    fn nonzero_exit_status_is_a_developer_error() {
+        let (result, logs) = run_stdio(3, serde_json::json!({ "n": 42 }));
+
+        let err = RuntimeError::from(result.unwrap_err());
+        assert!(matches!(err, RuntimeError::ActionDeveloper(_)));
+        assert_eq!(err.to_string(), "The action exited with status 3");
+        // The output of a failed guest is kept for debugging
+        assert!(logs.iter().any(|line| line.contains("\"n\":42")));
+    }
+
+    #[test]
+    fn abi_is_chosen_from_the_annotation_or_the_artifact() {
+        let annotated = |abi: &str| ActionCapabilities {
+            abi: Some(abi.to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(AbiKind::of(&Default::default(), false).unwrap(), AbiKind::Memory);
+        assert_eq!(AbiKind::of(&Default::default(), true).unwrap(), AbiKind::Component);
+        assert_eq!(AbiKind::of(&annotated("stdio"), false).unwrap(), AbiKind::Stdio);
+        assert!(AbiKind::of(&annotated("stdio"), true).is_err());
+        assert!(AbiKind::of(&annotated("grpc"), false).is_err());
+    }
+
+    #[test]
+    fn the_last_line_of_stdout_is_the_result() {
+        assert_eq!(
+            split_result(b"debug\n{\"ok\":true}\n"),
+            Some((&b"debug\n"[..], &b"{\"ok\":true}"[..]))
+        );
+        assert_eq!(split_result(b"{}"), Some((&b""[..], &b"{}"[..])));
+        assert_eq!(split_result(b"{\n  \"ok\": true\n}\n"), Some((&b""[..], &b"{\n  \"ok\": true\n}"[..])));
+        assert_eq!(split_result(b"\n"), None);
+    }
+}
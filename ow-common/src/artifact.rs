@@ -0,0 +1,199 @@
+use crate::engine::EngineOptions;
+use crate::module_cache::ModuleCache;
+use anyhow::{anyhow, Context};
+use std::sync::OnceLock;
+use wasmtime::component::Component;
+use wasmparser::{Parser, Payload};
+use wasmtime::{Engine, Module, Precompiled};
+
+/// The kinds of artifact that can be uploaded as the code of an action.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArtifactKind {
+    /// A core wasm module, as produced by any toolchain.
+    Module,
+    /// A wasm component, as produced by any toolchain.
+    Component,
+    /// A module precompiled by wasmtime into a `.cwasm` file.
+    PrecompiledModule,
+    /// A component precompiled by wasmtime into a `.cwasm` file.
+    PrecompiledComponent,
+}
+
+// Deserialization fails when the artifact was compiled with other settings than the engine
+const PRECOMPILED_SETTINGS: &str = "Failed to load the precompiled action, which must be \
+    compiled by wasmtime 27.0.0 with `-W epoch-interruption=y`, and with `-W fuel=1` if the \
+    executor meters fuel";
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const CORE_LAYER: [u8; 2] = [0x00, 0x00];
+const COMPONENT_LAYER: [u8; 2] = [0x01, 0x00];
+
+const WASI_NN_MODULE: &str = "wasi_ephemeral_nn";
+const WASI_NN_PACKAGE: &str = "wasi:nn/";
+
+/// Detects what kind of artifact `bytes` is from its header. Returns `None` if it is neither a
+/// wasm binary nor something precompiled by wasmtime.
+pub fn detect(bytes: &[u8]) -> Option<ArtifactKind> {
+    // Detection only reads the header, so any engine will do, whatever its configuration
+    static DETECTOR: OnceLock<Engine> = OnceLock::new();
+    let detector = DETECTOR.get_or_init(Engine::default);
+
+    if let Some(precompiled) = detector.detect_precompiled(bytes) {
+        return Some(match precompiled {
+            Precompiled::Module => ArtifactKind::PrecompiledModule,
+            Precompiled::Component => ArtifactKind::PrecompiledComponent,
+        });
+    }
+
+    // The magic is followed by a 2 byte version and a 2 byte layer, which tells modules and
+    // components apart
+    if bytes.len() < 8 || &bytes[0..4] != WASM_MAGIC {
+        return None;
+    }
+    match [bytes[6], bytes[7]] {
+        CORE_LAYER => Some(ArtifactKind::Module),
+        COMPONENT_LAYER => Some(ArtifactKind::Component),
+        _ => None,
+    }
+}
+
+/// Whether a wasm module imports `wasi_ephemeral_nn`, or a wasm component imports an interface
+/// of `wasi:nn`. Precompiled artifacts are not inspected, and are reported as not importing it.
+pub fn imports_wasi_nn(bytes: &[u8]) -> bool {
+    for payload in Parser::new(0).parse_all(bytes) {
+        let found = match payload {
+            Ok(Payload::ImportSection(imports)) => imports
+                .into_iter()
+                .any(|import| import.is_ok_and(|import| import.module == WASI_NN_MODULE)),
+            Ok(Payload::ComponentImportSection(imports)) => imports
+                .into_iter()
+                .any(|import| import.is_ok_and(|import| import.name.0.starts_with(WASI_NN_PACKAGE))),
+            Ok(_) => false,
+            // An artifact that fails to parse is reported when it is compiled
+            Err(_) => return false,
+        };
+        if found {
+            return true;
+        }
+    }
+    false
+}
+
+/// Compiles a core wasm module with `engine`, going through the `ModuleCache` if enabled, or
+/// deserializes it if it was precompiled and precompiled artifacts are trusted.
+#[tracing::instrument(skip_all)]
+pub fn load_module(
+    engine: &Engine,
+    options: &EngineOptions,
+    bytes: &[u8],
+) -> anyhow::Result<Module> {
+    match detect(bytes) {
+        Some(ArtifactKind::Module) => match ModuleCache::new(engine, options) {
+            // Cache entries were serialized by the executor itself, so they are trusted
+            Some(cache) => cache.get_or_compile(
+                bytes,
+                |path| unsafe { Module::deserialize_file(engine, path) },
+                || Module::new(engine, bytes),
+                |module| module.serialize(),
+            ),
+            None => Module::new(engine, bytes),
+        },
+        Some(ArtifactKind::PrecompiledModule) => {
+            check_trusted(options)?;
+            // deserialize could fail due to https://docs.wasmtime.dev/api/wasmtime/struct.Module.html#method.deserialize Unsafety
+            // module must've been precompiled with a matching version of wasmtime, and whoever
+            // uploads it is trusted to not craft malicious machine code
+            unsafe { Module::deserialize(engine, bytes) }.context(PRECOMPILED_SETTINGS)
+        }
+        Some(kind) => Err(anyhow!("Expected a wasm module, found a {:?}", kind)),
+        None => Err(anyhow!("The action is not a wasm module")),
+    }
+}
+
+/// Compiles a wasm component with `engine`, going through the `ModuleCache` if enabled, or
+/// deserializes it if it was precompiled and precompiled artifacts are trusted.
+#[tracing::instrument(skip_all)]
+pub fn load_component(
+    engine: &Engine,
+    options: &EngineOptions,
+    bytes: &[u8],
+) -> anyhow::Result<Component> {
+    match detect(bytes) {
+        Some(ArtifactKind::Component) => match ModuleCache::new(engine, options) {
+            Some(cache) => cache.get_or_compile(
+                bytes,
+                |path| unsafe { Component::deserialize_file(engine, path) },
+                || Component::new(engine, bytes),
+                |component| component.serialize(),
+            ),
+            None => Component::new(engine, bytes),
+        },
+        Some(ArtifactKind::PrecompiledComponent) => {
+            check_trusted(options)?;
+            // Same as `Module::deserialize`, see `load_module`
+            unsafe { Component::deserialize(engine, bytes) }.context(PRECOMPILED_SETTINGS)
+        }
+        Some(kind) => Err(anyhow!("Expected a wasm component, found a {:?}", kind)),
+        None => Err(anyhow!("The action is not a wasm component")),
+    }
+}
+
+fn check_trusted(options: &EngineOptions) -> anyhow::Result<()> {
+    if options.trust_precompiled {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Precompiled artifacts are not trusted, upload a .wasm file or set OW_TRUST_PRECOMPILED"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+    const EMPTY_COMPONENT: &[u8] = b"\0asm\x0d\0\x01\0";
+
+    #[test]
+    fn wasm_binaries_are_detected() {
+        assert_eq!(detect(EMPTY_MODULE), Some(ArtifactKind::Module));
+        assert_eq!(detect(EMPTY_COMPONENT), Some(ArtifactKind::Component));
+        assert_eq!(detect(b"PK\x03\x04"), None);
+    }
+
+    #[test]
+    fn wasi_nn_imports_are_detected() {
+        let nn_module = wat::parse_str(
+            r#"(module (import "wasi_ephemeral_nn" "load" (func (param i32 i32 i32 i32 i32) (result i32))))"#,
+        )
+        .unwrap();
+        let wasi_module = wat::parse_str(
+            r#"(module (import "wasi_snapshot_preview1" "proc_exit" (func (param i32))))"#,
+        )
+        .unwrap();
+        let nn_component = wat::parse_str(r#"(component (import "wasi:nn/graph@0.2.0-rc-2024-08-19" (instance)))"#)
+            .unwrap();
+
+        assert!(imports_wasi_nn(&nn_module));
+        assert!(!imports_wasi_nn(&wasi_module));
+        assert!(imports_wasi_nn(&nn_component));
+        assert!(!imports_wasi_nn(EMPTY_COMPONENT));
+        assert!(!imports_wasi_nn(b"PK\x03\x04"));
+    }
+
+    #[test]
+    fn precompiled_modules_need_to_be_trusted() {
+        let engine = Engine::default();
+        let precompiled = engine.precompile_module(EMPTY_MODULE).unwrap();
+
+        assert_eq!(detect(&precompiled), Some(ArtifactKind::PrecompiledModule));
+        assert!(load_module(&engine, &EngineOptions::default(), &precompiled).is_err());
+
+        let options = EngineOptions {
+            trust_precompiled: true,
+            ..Default::default()
+        };
+        assert!(load_module(&engine, &options, &precompiled).is_ok());
+    }
+}
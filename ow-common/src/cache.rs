@@ -0,0 +1,349 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name under which runtimes report their cache of instantiated modules.
+pub const INSTANCE_CACHE: &str = "instances";
+/// Name under which runtimes report their cache of downloaded models.
+pub const MODEL_CACHE: &str = "models";
+
+/// Which entry a full `Cache` evicts first.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EvictionPolicy {
+    /// The least recently used entry.
+    #[default]
+    Lru,
+    /// The least frequently used entry, the least recently used among those used as often.
+    Lfu,
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(anyhow::anyhow!("Unknown eviction policy {}, expected lru or lfu", s)),
+        }
+    }
+}
+
+/// Bounds of a `Cache`. A bound that is `None` is not enforced.
+#[derive(Debug, Clone, Default)]
+pub struct CacheConfig {
+    /// Total weight of the entries, in bytes.
+    pub capacity_bytes: Option<usize>,
+    /// Number of entries.
+    pub max_entries: Option<usize>,
+    /// How long an entry is kept after it was last used.
+    pub ttl: Option<Duration>,
+    pub policy: EvictionPolicy,
+}
+
+/// Counters of a `Cache` at some point in time.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries removed to make room for others or because their TTL expired.
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Contents of a `Cache`, as reported by the runtimes.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheInfo {
+    pub name: String,
+    pub stats: CacheStats,
+    pub entries: Vec<CacheEntryInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntryInfo {
+    pub key: String,
+    pub bytes: usize,
+    pub uses: u64,
+    /// Seconds since the entry was last used.
+    pub idle_secs: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    weight: usize,
+    last_used: Instant,
+    // Logical clock of the last use, so that entries used in the same instant are still ordered
+    tick: u64,
+    uses: u64,
+}
+
+struct Inner<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+/// A thread-safe cache bounded by total weight, number of entries and TTL.
+///
+/// Eviction scans all the entries, which is fine for the tens of modules or models an executor
+/// keeps, but not meant for large caches.
+pub struct Cache<K, V> {
+    config: CacheConfig,
+    inner: Mutex<Inner<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// Returns a clone of the value for `key`, and marks it as used.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let expired = match inner.entries.get(key) {
+            Some(entry) => self.is_expired(entry, now),
+            None => {
+                inner.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            inner.remove(key);
+            inner.stats.evictions += 1;
+            inner.stats.misses += 1;
+            return None;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.stats.hits += 1;
+
+        let entry = inner.entries.get_mut(key)?;
+        entry.last_used = now;
+        entry.tick = tick;
+        entry.uses += 1;
+        Some(entry.value.clone())
+    }
+
+    /// Inserts `value`, which takes `weight` bytes, evicting other entries until it fits.
+    /// Values heavier than the whole capacity are not cached.
+    pub fn insert(&self, key: K, value: V, weight: usize) {
+        if self.config.capacity_bytes.is_some_and(|capacity| weight > capacity) {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.remove(&key);
+
+        // Expired entries go first, then as many as needed by the policy
+        let expired: Vec<K> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| self.is_expired(entry, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            inner.remove(&key);
+            inner.stats.evictions += 1;
+        }
+        while self.is_full(&inner, weight) {
+            let Some(victim) = self.victim(&inner) else {
+                break;
+            };
+            inner.remove(&victim);
+            inner.stats.evictions += 1;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.stats.entries += 1;
+        inner.stats.bytes += weight;
+        inner.entries.insert(
+            key,
+            Entry {
+                value,
+                weight,
+                last_used: now,
+                tick,
+                uses: 1,
+            },
+        );
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.inner.lock().unwrap().remove(key)
+    }
+
+    /// Removes every entry whose key matches `predicate`, returning how many were removed.
+    pub fn remove_where(&self, predicate: impl Fn(&K) -> bool) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<K> = inner.entries.keys().filter(|key| predicate(key)).cloned().collect();
+        for key in &keys {
+            inner.remove(key);
+        }
+
+        keys.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+
+    /// Returns the stats and entries of the cache, with the keys formatted by `format_key`.
+    pub fn info(&self, name: &str, format_key: impl Fn(&K) -> String) -> CacheInfo {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let mut entries: Vec<CacheEntryInfo> = inner
+            .entries
+            .iter()
+            .map(|(key, entry)| CacheEntryInfo {
+                key: format_key(key),
+                bytes: entry.weight,
+                uses: entry.uses,
+                idle_secs: now.duration_since(entry.last_used).as_secs(),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+        CacheInfo {
+            name: name.to_owned(),
+            stats: inner.stats.clone(),
+            entries,
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry<V>, now: Instant) -> bool {
+        self.config
+            .ttl
+            .is_some_and(|ttl| now.duration_since(entry.last_used) > ttl)
+    }
+
+    fn is_full(&self, inner: &Inner<K, V>, weight: usize) -> bool {
+        let too_heavy = self
+            .config
+            .capacity_bytes
+            .is_some_and(|capacity| inner.stats.bytes + weight > capacity);
+        let too_many = self
+            .config
+            .max_entries
+            .is_some_and(|max| inner.entries.len() >= max);
+
+        too_heavy || too_many
+    }
+
+    fn victim(&self, inner: &Inner<K, V>) -> Option<K> {
+        let entries = inner.entries.iter();
+        let victim = match self.config.policy {
+            EvictionPolicy::Lru => entries.min_by_key(|(_, entry)| entry.tick),
+            EvictionPolicy::Lfu => entries.min_by_key(|(_, entry)| (entry.uses, entry.tick)),
+        };
+
+        victim.map(|(key, _)| key.clone())
+    }
+}
+
+impl<K: Eq + Hash, V> Inner<K, V> {
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.stats.entries -= 1;
+        self.stats.bytes -= entry.weight;
+        Some(entry.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(policy: EvictionPolicy) -> Cache<&'static str, u32> {
+        Cache::new(CacheConfig {
+            capacity_bytes: Some(30),
+            policy,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let cache = cache(EvictionPolicy::Lru);
+        cache.insert("a", 1, 10);
+        cache.insert("b", 2, 10);
+        cache.insert("c", 3, 10);
+        cache.get(&"a");
+
+        cache.insert("d", 4, 10);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().bytes, 30);
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used() {
+        let cache = cache(EvictionPolicy::Lfu);
+        cache.insert("a", 1, 10);
+        cache.insert("b", 2, 10);
+        cache.insert("c", 3, 10);
+        cache.get(&"a");
+        cache.get(&"b");
+
+        cache.insert("d", 4, 20);
+
+        assert_eq!(cache.get(&"c"), None);
+        assert_eq!(cache.get(&"d"), Some(4));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let cache = Cache::new(CacheConfig {
+            ttl: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        cache.insert("a", 1, 10);
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(cache.get(&"a"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 1, 1));
+    }
+
+    #[test]
+    fn matching_entries_are_removed() {
+        let cache = cache(EvictionPolicy::Lru);
+        cache.insert("a", 1, 10);
+        cache.insert("b", 2, 10);
+
+        assert_eq!(cache.remove_where(|key| *key == "a"), 1);
+
+        let info = cache.info("test", |key| key.to_string());
+        assert_eq!(info.entries.len(), 1);
+        assert_eq!(info.entries[0].key, "b");
+        assert_eq!(info.stats.bytes, 10);
+    }
+
+    #[test]
+    fn values_heavier_than_the_capacity_are_not_cached() {
+        let cache = cache(EvictionPolicy::Lru);
+        cache.insert("a", 1, 40);
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.stats().bytes, 0);
+    }
+}
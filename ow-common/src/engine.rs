@@ -0,0 +1,253 @@
+use crate::ActionCapabilities;
+use crate::cache::{CacheConfig, EvictionPolicy};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use wasmtime::{Config, Engine, EngineWeak, Store};
+
+/// How often the epoch of every engine created by `new_engine` is incremented.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+// Engines incremented by the epoch ticker thread, which runs while any of them is alive
+static TICKED_ENGINES: Mutex<Vec<EngineWeak>> = Mutex::new(Vec::new());
+
+/// Default capacity of the instance cache, in MB.
+pub const DEFAULT_INSTANCE_CACHE_MB: usize = 1024;
+/// Default capacity of the model cache, in MB.
+pub const DEFAULT_MODEL_CACHE_MB: usize = 2048;
+/// Default time that cached instances and models are kept after they were last used.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(600);
+/// Default timeout of model and image downloads.
+pub const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+/// Region of the S3 client when the AWS configuration does not set one.
+pub const DEFAULT_S3_REGION: &str = "eu-west-1";
+/// Default number of graphs a container keeps loaded from the `model` parameters.
+pub const DEFAULT_MAX_CONTAINER_GRAPHS: usize = 8;
+
+/// Number of threads the host can run in parallel, the default of the concurrency limits.
+pub fn default_parallelism() -> usize {
+    std::thread::available_parallelism().map_or(4, |threads| threads.get())
+}
+
+/// Engine settings shared by all the runtimes.
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// Runs every activation with a fuel budget and reports the fuel it consumed.
+    pub fuel_metering: bool,
+    /// Fuel budget for actions that do not set `fuel` in their annotations.
+    pub default_fuel: Option<u64>,
+    /// Memory limit for actions that do not set `memory_limit_mb` in their annotations.
+    pub default_memory_limit_mb: Option<u64>,
+    /// How many bytes of output an activation may log, `logs::DEFAULT_LOG_LIMIT` if not set.
+    pub log_limit_bytes: Option<usize>,
+    /// Accepts actions precompiled into `.cwasm` files. They are deserialized into machine code
+    /// without validation, so they must come from a trusted source.
+    pub trust_precompiled: bool,
+    /// Directory of the `ModuleCache`, which is disabled if not set.
+    pub module_cache_dir: Option<PathBuf>,
+    /// Directory under which actions may mount the host directory of their `dir` annotation.
+    /// Actions with a `dir` are rejected if not set.
+    pub guest_dir_base: Option<PathBuf>,
+    /// Bounds of the in-memory cache of instantiated modules, weighted by module size.
+    pub instance_cache: CacheConfig,
+    /// Bounds of the in-memory cache of downloaded models, weighted by model size.
+    pub model_cache: CacheConfig,
+    /// Timeout of the model and image downloads of the wasi-nn runtimes.
+    pub download_timeout: Duration,
+    /// Region of the S3 client of the wasi-nn runtimes, used when the AWS configuration of the
+    /// executor does not set one.
+    pub s3_region: String,
+    /// How many models an activation of `ow-wasmtime-nn-parallel` runs at the same time.
+    pub max_model_threads: usize,
+    /// How many graphs the wasi-nn runtimes keep loaded per container from the `model`
+    /// parameters, besides those of its `nn_graphs` annotation.
+    pub max_container_graphs: usize,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            fuel_metering: false,
+            default_fuel: None,
+            default_memory_limit_mb: None,
+            log_limit_bytes: None,
+            trust_precompiled: false,
+            module_cache_dir: None,
+            guest_dir_base: None,
+            instance_cache: CacheConfig {
+                capacity_bytes: Some(DEFAULT_INSTANCE_CACHE_MB * 1024 * 1024),
+                max_entries: None,
+                ttl: Some(DEFAULT_CACHE_TTL),
+                policy: EvictionPolicy::Lru,
+            },
+            model_cache: CacheConfig {
+                capacity_bytes: Some(DEFAULT_MODEL_CACHE_MB * 1024 * 1024),
+                max_entries: None,
+                ttl: Some(DEFAULT_CACHE_TTL),
+                policy: EvictionPolicy::Lru,
+            },
+            download_timeout: DEFAULT_DOWNLOAD_TIMEOUT,
+            s3_region: DEFAULT_S3_REGION.to_owned(),
+            max_model_threads: default_parallelism(),
+            max_container_graphs: DEFAULT_MAX_CONTAINER_GRAPHS,
+        }
+    }
+}
+
+/// Creates an engine with epoch interruption enabled, ticked by the epoch ticker thread.
+///
+/// Actions precompiled into `.cwasm` files must be compiled with the same settings, i.e. with
+/// `wasmtime compile -W epoch-interruption=y`, plus `-W fuel=<any>` when fuel metering is on.
+pub fn new_engine(options: &EngineOptions) -> Engine {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    config.consume_fuel(options.fuel_metering);
+
+    let engine = Engine::new(&config).expect("Failed to create the wasmtime engine");
+    tick_epochs(&engine);
+    engine
+}
+
+/// Adds the engine to those incremented every `EPOCH_TICK` by a single ticker thread, started
+/// for the first engine. The ticker holds the engines weakly and stops once all are dropped.
+fn tick_epochs(engine: &Engine) {
+    let mut engines = TICKED_ENGINES.lock().unwrap();
+    let start_ticker = engines.is_empty();
+    engines.push(engine.weak());
+    if !start_ticker {
+        return;
+    }
+
+    std::thread::Builder::new()
+        .name("epoch-ticker".to_owned())
+        .spawn(|| loop {
+            std::thread::sleep(EPOCH_TICK);
+
+            // Checked under the lock, so that an engine added right after the ticker stops
+            // starts a new one
+            let mut engines = TICKED_ENGINES.lock().unwrap();
+            engines.retain(|engine| match engine.upgrade() {
+                Some(engine) => {
+                    engine.increment_epoch();
+                    true
+                }
+                None => false,
+            });
+            if engines.is_empty() {
+                break;
+            }
+        })
+        .expect("Failed to spawn the epoch ticker thread");
+}
+
+/// Makes the store trap once the activation deadline has passed.
+/// `deadline` is given in milliseconds since the Unix epoch, as sent by OpenWhisk.
+/// A deadline of 0 means that the activation has no deadline.
+pub fn set_deadline<T>(store: &mut Store<T>, deadline: u64) {
+    if deadline == 0 {
+        store.set_epoch_deadline(u64::MAX);
+        return;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let ticks = deadline.saturating_sub(now) / EPOCH_TICK.as_millis() as u64;
+
+    // Always give the guest at least one tick, so that it is interrupted instead of never starting
+    store.set_epoch_deadline(ticks.max(1));
+}
+
+/// Gives the store its fuel budget, taken from the action annotations or the engine options.
+/// Returns the budget, or `None` if fuel metering is disabled.
+pub fn set_fuel<T>(
+    store: &mut Store<T>,
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+) -> anyhow::Result<Option<u64>> {
+    if !options.fuel_metering {
+        return Ok(None);
+    }
+
+    let budget = capabilities
+        .fuel
+        .or(options.default_fuel)
+        .unwrap_or(u64::MAX);
+    store.set_fuel(budget)?;
+
+    Ok(Some(budget))
+}
+
+/// Returns how much of the budget given by `set_fuel` the store has consumed.
+pub fn fuel_consumed<T>(store: &Store<T>, budget: Option<u64>) -> Option<u64> {
+    let budget = budget?;
+    let remaining = store.get_fuel().unwrap_or(0);
+    Some(budget.saturating_sub(remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActivationFailure, ActivationResponse, ActivationResponseStatus, RuntimeError};
+    use wasmtime::{Instance, Module};
+
+    const INFINITE_LOOP: &str = r#"(module (func (export "run") (loop $spin (br $spin))))"#;
+
+    fn run_forever<T>(engine: &Engine, store: &mut Store<T>) -> RuntimeError {
+        let module = Module::new(engine, INFINITE_LOOP).unwrap();
+        let instance = Instance::new(&mut *store, &module, &[]).unwrap();
+        let run = instance.get_typed_func::<(), ()>(&mut *store, "run").unwrap();
+
+        RuntimeError::from(run.call(&mut *store, ()).unwrap_err())
+    }
+
+    #[test]
+    fn test_guests_are_interrupted_at_the_deadline() {
+        let engine = new_engine(&EngineOptions::default());
+        let mut store = Store::new(&engine, ());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        set_deadline(&mut store, now + 50);
+
+        let err = run_forever(&engine, &mut store);
+
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+        assert_eq!(err.to_string(), "The action exceeded its time limit.");
+    }
+
+    #[test]
+    fn test_guests_run_out_of_fuel_and_report_it() {
+        let options = EngineOptions {
+            fuel_metering: true,
+            default_fuel: Some(10_000),
+            ..Default::default()
+        };
+        let engine = new_engine(&options);
+        let mut store = Store::new(&engine, ());
+        set_deadline(&mut store, 0);
+        let budget = set_fuel(&mut store, &options, &ActionCapabilities::default()).unwrap();
+        assert_eq!(budget, Some(10_000));
+
+        let err = run_forever(&engine, &mut store);
+
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+        assert_eq!(err.to_string(), "The action ran out of fuel.");
+        assert_eq!(fuel_consumed(&store, budget), Some(10_000));
+
+        let failure = ActivationFailure::new(err, fuel_consumed(&store, budget), Vec::new());
+        let response = serde_json::to_value(ActivationResponse::error(failure)).unwrap();
+        assert_eq!(response["fuel_consumed"], 10_000);
+    }
+
+    #[test]
+    fn test_fuel_is_not_reported_without_metering() {
+        let options = EngineOptions::default();
+        let engine = new_engine(&options);
+        let mut store = Store::new(&engine, ());
+
+        let budget = set_fuel(&mut store, &options, &ActionCapabilities::default()).unwrap();
+
+        assert_eq!(budget, None);
+        assert_eq!(fuel_consumed(&store, budget), None);
+    }
+}
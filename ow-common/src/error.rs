@@ -0,0 +1,139 @@
+use crate::ActivationResponseStatus;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use wasmtime::Trap;
+
+/// Why an activation could not produce a result, as reported back to OpenWhisk.
+///
+/// Errors are classified where they are raised, with `RuntimeError::developer`,
+/// `RuntimeError::internal` or the `Classify` methods. Any `anyhow::Error` converts into a
+/// `RuntimeError`: a `RuntimeError` wrapped by `anyhow` keeps its variant, and guest traps, exceeded
+/// limits, buffers outside of the guest memory and unclassified errors are developer errors, so
+/// that only failures known to be of the runtime are retried.
+#[derive(Debug, thiserror::Error)]
+pub enum RuntimeError {
+    /// The action itself is at fault: it trapped, exceeded its limits, does not export what the
+    /// runtime expects or returned something that is not valid JSON.
+    #[error("{0:#}")]
+    ActionDeveloper(anyhow::Error),
+    /// The runtime failed independently of the action, e.g. a download or the module cache.
+    #[error("{0:#}")]
+    WhiskInternal(anyhow::Error),
+}
+
+impl RuntimeError {
+    pub fn developer(message: impl std::fmt::Display) -> Self {
+        RuntimeError::ActionDeveloper(anyhow::anyhow!("{}", message))
+    }
+
+    pub fn internal(message: impl std::fmt::Display) -> Self {
+        RuntimeError::WhiskInternal(anyhow::anyhow!("{}", message))
+    }
+
+    pub fn status(&self) -> ActivationResponseStatus {
+        match self {
+            RuntimeError::ActionDeveloper(_) => ActivationResponseStatus::ActionDeveloperError,
+            RuntimeError::WhiskInternal(_) => ActivationResponseStatus::WhiskInternalError,
+        }
+    }
+}
+
+impl From<anyhow::Error> for RuntimeError {
+    fn from(err: anyhow::Error) -> Self {
+        // A classified error keeps its variant, and the context added to it since
+        match err.downcast_ref::<RuntimeError>() {
+            Some(RuntimeError::ActionDeveloper(_)) => return RuntimeError::ActionDeveloper(err),
+            Some(RuntimeError::WhiskInternal(_)) => return RuntimeError::WhiskInternal(err),
+            None => {}
+        }
+
+        match err.downcast_ref::<Trap>() {
+            Some(Trap::Interrupt) => RuntimeError::developer("The action exceeded its time limit."),
+            Some(Trap::OutOfFuel) => RuntimeError::developer("The action ran out of fuel."),
+            _ => RuntimeError::ActionDeveloper(err),
+        }
+    }
+}
+
+/// Classifies the error of a result where it is raised, as `anyhow::Context` adds context to it.
+pub trait Classify<T> {
+    /// The action is at fault for the error.
+    fn developer_error(self) -> Result<T, RuntimeError>;
+    /// The runtime is at fault for the error.
+    fn internal_error(self) -> Result<T, RuntimeError>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn developer_error(self) -> Result<T, RuntimeError> {
+        self.map_err(|err| RuntimeError::ActionDeveloper(err.into()))
+    }
+
+    fn internal_error(self) -> Result<T, RuntimeError> {
+        self.map_err(|err| RuntimeError::WhiskInternal(err.into()))
+    }
+}
+
+/// Runs `f`, turning a panic into an internal error, so that a bug hit by one activation fails
+/// that activation instead of taking down the thread that runs it.
+pub fn catch_panic<T, E: From<RuntimeError>>(f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        Err(RuntimeError::internal(format!("The runtime panicked: {}", panic_message(&*panic))).into())
+    })
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traps_are_developer_errors() {
+        let err = RuntimeError::from(anyhow::Error::from(Trap::Interrupt));
+
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+        assert_eq!(err.to_string(), "The action exceeded its time limit.");
+    }
+
+    #[test]
+    fn wrapped_runtime_errors_keep_their_variant() {
+        let err: anyhow::Error = RuntimeError::developer("Failed to get set_input").into();
+        let err = RuntimeError::from(err);
+
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+        assert_eq!(err.to_string(), "Failed to get set_input");
+    }
+
+    #[test]
+    fn unclassified_errors_are_developer_errors() {
+        let err = RuntimeError::from(anyhow::anyhow!("Failed to instantiate the module"));
+
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+    }
+
+    #[test]
+    fn classified_errors_keep_their_variant_through_context() {
+        let result: Result<(), _> = Err(anyhow::anyhow!("connection refused")).internal_error();
+        let err = anyhow::Error::from(result.unwrap_err()).context("Failed to download the model");
+        let err = RuntimeError::from(err);
+
+        assert_eq!(err.status(), ActivationResponseStatus::WhiskInternalError);
+        assert_eq!(err.to_string(), "Failed to download the model: connection refused");
+    }
+
+    #[test]
+    fn panics_are_internal_errors() {
+        let result: Result<(), RuntimeError> = catch_panic(|| panic!("index out of bounds"));
+        let err = result.unwrap_err();
+
+        assert_eq!(err.status(), ActivationResponseStatus::WhiskInternalError);
+        assert_eq!(err.to_string(), "The runtime panicked: index out of bounds");
+    }
+}
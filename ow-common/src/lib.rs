@@ -1,3 +1,16 @@
+mod error;
 mod types;
+pub mod abi;
+pub mod artifact;
+pub mod cache;
+pub mod engine;
+pub mod limits;
+pub mod logs;
+pub mod metrics;
+pub mod module_cache;
+#[cfg(feature = "wasi-nn")]
+pub mod nn;
 pub mod util;
+pub mod wasi;
+pub use error::*;
 pub use types::*;
@@ -0,0 +1,188 @@
+use crate::engine::EngineOptions;
+use crate::{ActionCapabilities, RuntimeError};
+use std::fmt;
+use wasmtime::ResourceLimiter;
+
+/// Maximum number of elements a single guest table can grow to, for actions that do not set the
+/// `table_elements_limit` annotation.
+pub const MAX_TABLE_ELEMENTS: usize = 100_000;
+
+/// A `ResourceLimiter` in the style of wasmtime's `StoreLimits`, whose caps come from the
+/// `memory_limit_mb` and `table_elements_limit` annotations of the action. Growing past a limit
+/// traps the guest with a `LimitExceeded` error instead of letting it exhaust the memory of the
+/// host.
+#[derive(Debug, Clone)]
+pub struct GuestLimits {
+    memory_size: Option<usize>,
+    table_elements: usize,
+}
+
+impl GuestLimits {
+    /// Fails with a developer error if an annotation does not fit in the address space of the
+    /// host.
+    pub fn new(
+        options: &EngineOptions,
+        capabilities: &ActionCapabilities,
+    ) -> Result<Self, RuntimeError> {
+        let memory_limit_mb = capabilities
+            .memory_limit_mb
+            .or(options.default_memory_limit_mb);
+        let memory_size = memory_limit_mb
+            .map(|mb| {
+                mb.checked_mul(1024 * 1024)
+                    .and_then(|bytes| usize::try_from(bytes).ok())
+                    .ok_or_else(|| {
+                        RuntimeError::developer(format!(
+                            "The memory limit of {} MB is too large.",
+                            mb
+                        ))
+                    })
+            })
+            .transpose()?;
+
+        let table_elements = match capabilities.table_elements_limit {
+            Some(limit) => usize::try_from(limit).map_err(|_| {
+                RuntimeError::developer(format!(
+                    "The table limit of {} elements is too large.",
+                    limit
+                ))
+            })?,
+            None => MAX_TABLE_ELEMENTS,
+        };
+
+        Ok(Self {
+            memory_size,
+            table_elements,
+        })
+    }
+}
+
+impl Default for GuestLimits {
+    fn default() -> Self {
+        Self {
+            memory_size: None,
+            table_elements: MAX_TABLE_ELEMENTS,
+        }
+    }
+}
+
+impl ResourceLimiter for GuestLimits {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        match self.memory_size {
+            Some(limit) if desired > limit => Err(LimitExceeded::Memory(limit).into()),
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if desired > self.table_elements {
+            return Err(LimitExceeded::Table(self.table_elements).into());
+        }
+        Ok(true)
+    }
+}
+
+/// Error raised when a guest tries to grow a memory or a table past its `GuestLimits`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitExceeded {
+    /// The limit of a linear memory, in bytes.
+    Memory(usize),
+    /// The limit of a table, in elements.
+    Table(usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Memory(limit) => write!(
+                f,
+                "The action exceeded its memory limit of {} MB.",
+                limit / (1024 * 1024)
+            ),
+            LimitExceeded::Table(limit) => write!(
+                f,
+                "The action exceeded its table limit of {} elements.",
+                limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActivationResponseStatus;
+
+    #[test]
+    fn test_memory_growth_is_capped_by_annotation() {
+        let capabilities = ActionCapabilities {
+            memory_limit_mb: Some(1),
+            ..Default::default()
+        };
+        let mut limits = GuestLimits::new(&EngineOptions::default(), &capabilities).unwrap();
+
+        assert!(limits.memory_growing(0, 1024 * 1024, None).unwrap());
+
+        let err = limits.memory_growing(0, 1024 * 1024 + 1, None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::Memory(1024 * 1024))
+        );
+    }
+
+    #[test]
+    fn test_annotation_overrides_default_memory_limit() {
+        let options = EngineOptions {
+            default_memory_limit_mb: Some(1),
+            ..Default::default()
+        };
+        let capabilities = ActionCapabilities {
+            memory_limit_mb: Some(2),
+            ..Default::default()
+        };
+        let mut limits = GuestLimits::new(&options, &capabilities).unwrap();
+
+        assert!(limits.memory_growing(0, 2 * 1024 * 1024, None).unwrap());
+    }
+
+    #[test]
+    fn test_oversized_memory_limit_is_a_developer_error() {
+        let capabilities = ActionCapabilities {
+            memory_limit_mb: Some(u64::MAX),
+            ..Default::default()
+        };
+
+        let err = GuestLimits::new(&EngineOptions::default(), &capabilities).unwrap_err();
+
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+    }
+
+    #[test]
+    fn test_table_growth_is_capped_by_annotation() {
+        let capabilities = ActionCapabilities {
+            table_elements_limit: Some(10),
+            ..Default::default()
+        };
+        let mut limits = GuestLimits::new(&EngineOptions::default(), &capabilities).unwrap();
+
+        assert!(limits.table_growing(0, 10, None).unwrap());
+
+        let err = limits.table_growing(0, 11, None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::Table(10))
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use crate::engine::EngineOptions;
+use bytes::Bytes;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use wasmtime_wasi::{
+    async_trait, HostOutputStream, StdoutStream, StreamResult, Subscribe, WasiCtxBuilder,
+};
+
+/// Written to stdout and stderr after the logs of every activation, so that OpenWhisk knows
+/// where they end.
+pub const ACTIVATION_LOG_SENTINEL: &str = "XXX_THE_END_OF_A_WHISK_ACTIVATION_XXX";
+
+/// How many bytes of output an activation may log when `OW_LOG_LIMIT_BYTES` is not set.
+pub const DEFAULT_LOG_LIMIT: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogStream {
+    Stdout = 0,
+    Stderr = 1,
+}
+
+impl LogStream {
+    fn name(self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        }
+    }
+}
+
+struct LogLine {
+    time: SystemTime,
+    stream: LogStream,
+    text: String,
+}
+
+#[derive(Default)]
+struct Captured {
+    lines: Vec<LogLine>,
+    // Output of each stream that does not end with a newline yet
+    partial: [Vec<u8>; 2],
+    size: usize,
+    truncated: bool,
+}
+
+impl Captured {
+    fn push(&mut self, stream: LogStream, text: &[u8], limit: usize) {
+        if self.truncated || self.size + text.len() > limit {
+            self.truncated = true;
+            return;
+        }
+
+        self.size += text.len();
+        self.lines.push(LogLine {
+            time: SystemTime::now(),
+            stream,
+            text: String::from_utf8_lossy(text).into_owned(),
+        });
+    }
+
+    /// Keeps `rest` until the guest finishes the line. Unfinished lines count toward the limit,
+    /// so a line that would not fit is logged up to the limit and the rest of the output is
+    /// dropped, instead of growing without bound while the guest writes no newline.
+    fn keep_partial(&mut self, stream: LogStream, rest: &[u8], limit: usize) {
+        let other = self.partial[1 - stream as usize].len();
+        let room = limit.saturating_sub(self.size + other);
+
+        if rest.len() > room {
+            if room > 0 {
+                self.push(stream, &rest[..room], limit);
+            }
+            self.truncated = true;
+            self.partial[stream as usize] = Vec::new();
+        } else {
+            self.partial[stream as usize] = rest.to_vec();
+        }
+    }
+}
+
+/// Captures the stdout and stderr of the stores of one activation, instead of inheriting the
+/// ones of the executor. Output beyond the limit is dropped rather than failing the guest.
+#[derive(Clone)]
+pub struct GuestLogs {
+    captured: Arc<Mutex<Captured>>,
+    limit: usize,
+}
+
+impl GuestLogs {
+    pub fn new(options: &EngineOptions) -> Self {
+        Self {
+            captured: Arc::new(Mutex::new(Captured::default())),
+            limit: options.log_limit_bytes.unwrap_or(DEFAULT_LOG_LIMIT),
+        }
+    }
+
+    /// Makes the WASI context write its stdout and stderr to these logs.
+    pub fn attach(&self, builder: &mut WasiCtxBuilder) {
+        builder
+            .stdout(self.pipe(LogStream::Stdout))
+            .stderr(self.pipe(LogStream::Stderr));
+    }
+
+    /// Makes the WASI context write only its stderr to these logs, for guests that return their
+    /// result on stdout.
+    pub fn attach_stderr(&self, builder: &mut WasiCtxBuilder) {
+        builder.stderr(self.pipe(LogStream::Stderr));
+    }
+
+    /// Logs `text` as if the guest had written it to stdout.
+    pub fn log_stdout(&self, text: &[u8]) {
+        let _ = self.pipe(LogStream::Stdout).write(Bytes::copy_from_slice(text));
+    }
+
+    fn pipe(&self, stream: LogStream) -> LogPipe {
+        LogPipe {
+            stream,
+            captured: Arc::clone(&self.captured),
+            limit: self.limit,
+        }
+    }
+
+    /// Formats the captured lines as `<timestamp> <stream>: [<activation id>] <line>` and writes
+    /// them to the stdout and stderr of the executor, each followed by the activation sentinel.
+    /// Returns the formatted lines.
+    pub fn finish(&self, activation_id: &str) -> Vec<String> {
+        let mut captured = self.captured.lock().unwrap();
+
+        for stream in [LogStream::Stdout, LogStream::Stderr] {
+            let rest = std::mem::take(&mut captured.partial[stream as usize]);
+            if !rest.is_empty() {
+                let limit = self.limit;
+                captured.push(stream, &rest, limit);
+            }
+        }
+        if captured.truncated {
+            captured.lines.push(LogLine {
+                time: SystemTime::now(),
+                stream: LogStream::Stderr,
+                text: format!("Logs were truncated after {} bytes.", self.limit),
+            });
+        }
+
+        let lines: Vec<(LogStream, String)> = captured
+            .lines
+            .drain(..)
+            .map(|line| {
+                let text = format!(
+                    "{} {}: [{}] {}",
+                    humantime::format_rfc3339_nanos(line.time),
+                    line.stream.name(),
+                    activation_id,
+                    line.text
+                );
+                (line.stream, text)
+            })
+            .collect();
+
+        // Lock both streams, so that the logs of concurrent activations do not interleave
+        let mut stdout = std::io::stdout().lock();
+        let mut stderr = std::io::stderr().lock();
+        for (stream, line) in &lines {
+            let _ = match stream {
+                LogStream::Stdout => writeln!(stdout, "{}", line),
+                LogStream::Stderr => writeln!(stderr, "{}", line),
+            };
+        }
+        let _ = writeln!(stdout, "{}", ACTIVATION_LOG_SENTINEL);
+        let _ = writeln!(stderr, "{}", ACTIVATION_LOG_SENTINEL);
+        let _ = stdout.flush();
+        let _ = stderr.flush();
+
+        lines.into_iter().map(|(_, line)| line).collect()
+    }
+}
+
+#[derive(Clone)]
+struct LogPipe {
+    stream: LogStream,
+    captured: Arc<Mutex<Captured>>,
+    limit: usize,
+}
+
+impl HostOutputStream for LogPipe {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let mut captured = self.captured.lock().unwrap();
+        if captured.truncated {
+            return Ok(());
+        }
+        let mut partial = std::mem::take(&mut captured.partial[self.stream as usize]);
+        partial.extend_from_slice(&bytes);
+
+        let mut lines = partial.split(|byte| *byte == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            if lines.peek().is_none() {
+                // The last piece has no newline yet
+                captured.keep_partial(self.stream, line, self.limit);
+                break;
+            }
+            captured.push(self.stream, line, self.limit);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        // Output beyond the limit is dropped, so the pipe always accepts writes
+        Ok(usize::MAX)
+    }
+}
+
+#[async_trait]
+impl Subscribe for LogPipe {
+    async fn ready(&mut self) {}
+}
+
+impl StdoutStream for LogPipe {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(limit: usize) -> GuestLogs {
+        GuestLogs::new(&EngineOptions {
+            log_limit_bytes: Some(limit),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn lines_are_split_and_tagged() {
+        let logs = logs(DEFAULT_LOG_LIMIT);
+        let mut stdout = logs.pipe(LogStream::Stdout);
+        let mut stderr = logs.pipe(LogStream::Stderr);
+
+        stdout.write(Bytes::from_static(b"hello\nwor")).unwrap();
+        stderr.write(Bytes::from_static(b"oops\n")).unwrap();
+        stdout.write(Bytes::from_static(b"ld")).unwrap();
+
+        let lines = logs.finish("a1");
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" stdout: [a1] hello"));
+        assert!(lines[1].ends_with(" stderr: [a1] oops"));
+        assert!(lines[2].ends_with(" stdout: [a1] world"));
+    }
+
+    #[test]
+    fn output_beyond_the_limit_is_dropped() {
+        let logs = logs(8);
+        let mut stdout = logs.pipe(LogStream::Stdout);
+
+        stdout.write(Bytes::from_static(b"12345\n67890\n")).unwrap();
+
+        let lines = logs.finish("a1");
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("12345"));
+        assert!(lines[1].ends_with("Logs were truncated after 8 bytes."));
+    }
+
+    #[test]
+    fn output_without_newlines_counts_toward_the_limit() {
+        let logs = logs(8);
+        let mut stdout = logs.pipe(LogStream::Stdout);
+
+        stdout.write(Bytes::from_static(b"12345")).unwrap();
+        stdout.write(Bytes::from_static(b"67890")).unwrap();
+        assert!(logs.captured.lock().unwrap().partial[0].is_empty());
+        stdout.write(Bytes::from_static(b"more")).unwrap();
+
+        let lines = logs.finish("a1");
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("12345678"));
+        assert!(lines[1].ends_with("Logs were truncated after 8 bytes."));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::cache::CacheInfo;
+use crate::ActivationResponseStatus;
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Where the runtimes download models and images from.
+#[derive(Debug, Clone, Copy)]
+pub enum DownloadSource {
+    Http,
+    S3,
+}
+
+impl DownloadSource {
+    fn label(&self) -> &'static str {
+        match self {
+            DownloadSource::Http => "http",
+            DownloadSource::S3 => "s3",
+        }
+    }
+}
+
+/// The Prometheus metrics of the executor and its runtimes.
+pub struct Metrics {
+    registry: Registry,
+    activations: IntCounterVec,
+    activation_duration: HistogramVec,
+    init_duration: HistogramVec,
+    in_flight: IntGauge,
+    rejected: IntCounterVec,
+    downloaded_bytes: IntCounterVec,
+    cache_hits: IntCounterVec,
+    cache_misses: IntCounterVec,
+    cache_evictions: IntCounterVec,
+    cache_hit_ratio: GaugeVec,
+    cache_entries: IntGaugeVec,
+    cache_bytes: IntGaugeVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Returns the metrics shared by the whole process.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Self {
+        // From 1 ms to about 65 s, which covers both small actions and model inference
+        let buckets = exponential_buckets(0.001, 2.0, 17).unwrap();
+
+        let activations = IntCounterVec::new(
+            Opts::new("ow_activations_total", "Activations by container and outcome"),
+            &["container", "outcome"],
+        )
+        .unwrap();
+        let activation_duration = HistogramVec::new(
+            HistogramOpts::new("ow_activation_duration_seconds", "Latency of activations")
+                .buckets(buckets.clone()),
+            &["container", "outcome"],
+        )
+        .unwrap();
+        let init_duration = HistogramVec::new(
+            HistogramOpts::new("ow_init_duration_seconds", "Latency of container inits")
+                .buckets(buckets),
+            &["outcome"],
+        )
+        .unwrap();
+        let in_flight =
+            IntGauge::new("ow_activations_in_flight", "Activations currently running").unwrap();
+        let rejected = IntCounterVec::new(
+            Opts::new("ow_activations_rejected_total", "Activations rejected by admission control"),
+            &["reason"],
+        )
+        .unwrap();
+        let downloaded_bytes = IntCounterVec::new(
+            Opts::new("ow_downloaded_bytes_total", "Bytes of models and images downloaded"),
+            &["source"],
+        )
+        .unwrap();
+
+        let cache_counter = |name: &str, help: &str| {
+            IntCounterVec::new(Opts::new(name, help), &["cache"]).unwrap()
+        };
+        let cache_gauge = |name: &str, help: &str| {
+            IntGaugeVec::new(Opts::new(name, help), &["cache"]).unwrap()
+        };
+        let cache_hits = cache_counter("ow_cache_hits_total", "Lookups that found an entry");
+        let cache_misses = cache_counter("ow_cache_misses_total", "Lookups that found no entry");
+        let cache_evictions = cache_counter("ow_cache_evictions_total", "Entries evicted");
+        let cache_entries = cache_gauge("ow_cache_entries", "Entries in the cache");
+        let cache_bytes = cache_gauge("ow_cache_bytes", "Size of the entries in the cache");
+        let cache_hit_ratio = GaugeVec::new(
+            Opts::new("ow_cache_hit_ratio", "Hits over lookups since the executor started"),
+            &["cache"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(activations.clone())).unwrap();
+        registry.register(Box::new(activation_duration.clone())).unwrap();
+        registry.register(Box::new(init_duration.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(rejected.clone())).unwrap();
+        registry.register(Box::new(downloaded_bytes.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry.register(Box::new(cache_evictions.clone())).unwrap();
+        registry.register(Box::new(cache_hit_ratio.clone())).unwrap();
+        registry.register(Box::new(cache_entries.clone())).unwrap();
+        registry.register(Box::new(cache_bytes.clone())).unwrap();
+
+        Self {
+            registry,
+            activations,
+            activation_duration,
+            init_duration,
+            in_flight,
+            rejected,
+            downloaded_bytes,
+            cache_hits,
+            cache_misses,
+            cache_evictions,
+            cache_hit_ratio,
+            cache_entries,
+            cache_bytes,
+        }
+    }
+
+    pub fn activation_started(&self) {
+        self.in_flight.inc();
+    }
+
+    pub fn activation_finished(
+        &self,
+        container_id: &str,
+        status: &ActivationResponseStatus,
+        duration: Duration,
+    ) {
+        let labels = [container_id, outcome(status)];
+        self.in_flight.dec();
+        self.activations.with_label_values(&labels).inc();
+        self.activation_duration
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn activation_rejected(&self, reason: &str) {
+        self.rejected.with_label_values(&[reason]).inc();
+    }
+
+    pub fn init_finished(&self, success: bool, duration: Duration) {
+        let outcome = if success { "success" } else { "error" };
+        self.init_duration
+            .with_label_values(&[outcome])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Drops the series of a destroyed container, so that they do not pile up.
+    pub fn container_destroyed(&self, container_id: &str) {
+        for status in [
+            ActivationResponseStatus::Success,
+            ActivationResponseStatus::ApplicationError,
+            ActivationResponseStatus::ActionDeveloperError,
+            ActivationResponseStatus::WhiskInternalError,
+        ] {
+            let labels = [container_id, outcome(&status)];
+            let _ = self.activations.remove_label_values(&labels);
+            let _ = self.activation_duration.remove_label_values(&labels);
+        }
+    }
+
+    pub fn downloaded(&self, source: DownloadSource, bytes: usize) {
+        self.downloaded_bytes
+            .with_label_values(&[source.label()])
+            .inc_by(bytes as u64);
+    }
+
+    /// Encodes every metric in the Prometheus text format, taking the cache metrics from `caches`.
+    pub fn encode(&self, caches: &[CacheInfo]) -> anyhow::Result<String> {
+        for cache in caches {
+            let labels = [cache.name.as_str()];
+            let stats = &cache.stats;
+            let lookups = stats.hits + stats.misses;
+
+            catch_up(&self.cache_hits.with_label_values(&labels), stats.hits);
+            catch_up(&self.cache_misses.with_label_values(&labels), stats.misses);
+            catch_up(&self.cache_evictions.with_label_values(&labels), stats.evictions);
+            self.cache_entries.with_label_values(&labels).set(stats.entries as i64);
+            self.cache_bytes.with_label_values(&labels).set(stats.bytes as i64);
+            if lookups > 0 {
+                self.cache_hit_ratio
+                    .with_label_values(&labels)
+                    .set(stats.hits as f64 / lookups as f64);
+            }
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Increments `counter` up to `total`, as counted by a cache since the executor started.
+fn catch_up(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+fn outcome(status: &ActivationResponseStatus) -> &'static str {
+    match status {
+        ActivationResponseStatus::Success => "success",
+        ActivationResponseStatus::ApplicationError => "application_error",
+        ActivationResponseStatus::ActionDeveloperError => "developer_error",
+        ActivationResponseStatus::WhiskInternalError => "internal_error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheStats;
+
+    #[test]
+    fn activations_and_caches_are_encoded() {
+        let metrics = Metrics::new();
+        metrics.activation_started();
+        metrics.activation_finished(
+            "container",
+            &ActivationResponseStatus::Success,
+            Duration::from_millis(3),
+        );
+        let caches = [CacheInfo {
+            name: "wasmtime/instances".to_owned(),
+            stats: CacheStats {
+                hits: 3,
+                misses: 1,
+                ..Default::default()
+            },
+            entries: Vec::new(),
+        }];
+
+        let text = metrics.encode(&caches).unwrap();
+
+        assert!(text.contains(r#"ow_activations_total{container="container",outcome="success"} 1"#));
+        assert!(text.contains("ow_activations_in_flight 0"));
+        assert!(text.contains(r#"ow_cache_hit_ratio{cache="wasmtime/instances"} 0.75"#));
+        assert!(text.contains("# TYPE ow_cache_hits_total counter"));
+        assert!(text.contains(r#"ow_cache_hits_total{cache="wasmtime/instances"} 3"#));
+    }
+
+    #[test]
+    fn cache_counters_follow_the_cache_stats() {
+        let metrics = Metrics::new();
+        let caches = |hits| {
+            [CacheInfo {
+                name: "wasmtime/instances".to_owned(),
+                stats: CacheStats {
+                    hits,
+                    ..Default::default()
+                },
+                entries: Vec::new(),
+            }]
+        };
+
+        metrics.encode(&caches(2)).unwrap();
+        let text = metrics.encode(&caches(5)).unwrap();
+
+        assert!(text.contains(r#"ow_cache_hits_total{cache="wasmtime/instances"} 5"#));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::engine::EngineOptions;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, warn};
+use wasmtime::Engine;
+
+/// Content-addressed cache of compiled modules and components on disk, so that actions compiled
+/// before a restart of the executor do not have to be compiled again.
+///
+/// Entries are stored as `<dir>/<engine>/<sha256 of the wasm>.cwasm`, where `<engine>` is derived
+/// from the wasmtime version and the engine configuration. Entries compiled by a different engine
+/// end up in another directory, and any entry that still fails to deserialize is compiled again.
+///
+/// Entries are loaded as machine code without validation, so the directory must only be
+/// writable by the executor.
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    /// Returns the cache for `engine`, or `None` if `module_cache_dir` is not set.
+    pub fn new(engine: &Engine, options: &EngineOptions) -> Option<Self> {
+        let root = options.module_cache_dir.as_ref()?;
+
+        let mut hasher = DefaultHasher::new();
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+
+        Some(Self {
+            dir: root.join(format!("{:016x}", hasher.finish())),
+        })
+    }
+
+    fn path(&self, bytes: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:x}.cwasm", Sha256::digest(bytes)))
+    }
+
+    /// Deserializes the entry for `bytes` if there is a compatible one. Otherwise compiles it
+    /// and stores the result for the next time. Failing to store it is not an error.
+    #[tracing::instrument(skip_all, fields(path))]
+    pub fn get_or_compile<T>(
+        &self,
+        bytes: &[u8],
+        deserialize: impl FnOnce(&Path) -> anyhow::Result<T>,
+        compile: impl FnOnce() -> anyhow::Result<T>,
+        serialize: impl FnOnce(&T) -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<T> {
+        let path = self.path(bytes);
+        tracing::Span::current().record("path", tracing::field::display(path.display()));
+
+        if path.exists() {
+            match deserialize(&path) {
+                Ok(compiled) => {
+                    debug!("Module found in disk cache at {}", path.display());
+                    return Ok(compiled);
+                }
+                Err(e) => warn!("Ignoring disk cache entry {}: {}", path.display(), e),
+            }
+        }
+
+        let compiled = compile()?;
+
+        if let Err(e) = serialize(&compiled).and_then(|serialized| self.store(&path, &serialized)) {
+            error!("Error writing {} to the disk cache: {}", path.display(), e);
+        }
+
+        Ok(compiled)
+    }
+
+    fn store(&self, path: &Path, serialized: &[u8]) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        // Write to a temporary file first, so that a concurrent reader never sees half an entry.
+        // Every store gets its own file, which is removed if the store does not finish
+        let mut tmp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        tmp.write_all(serialized)?;
+        tmp.persist(path)?;
+
+        Ok(())
+    }
+
+    /// Makes the entries stored so far durable before the executor exits, by syncing them and
+    /// their directories to disk. The temporary files of stores still in progress are left alone.
+    pub fn flush(options: &EngineOptions) -> anyhow::Result<()> {
+        let Some(root) = options.module_cache_dir.as_ref().filter(|root| root.is_dir()) else {
+            return Ok(());
+        };
+
+        for dir in std::fs::read_dir(root)? {
+            let dir = dir?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "cwasm") {
+                    std::fs::File::open(&path)?.sync_all()?;
+                }
+            }
+            std::fs::File::open(&dir)?.sync_all()?;
+        }
+
+        debug!("Flushed the disk cache at {}", root.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Module;
+
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn cache(engine: &Engine, name: &str) -> ModuleCache {
+        let dir = std::env::temp_dir().join(format!("ow-module-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        ModuleCache::new(
+            engine,
+            &EngineOptions {
+                module_cache_dir: Some(dir),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn load(cache: &ModuleCache, engine: &Engine, compiled: &mut bool) -> Module {
+        cache
+            .get_or_compile(
+                EMPTY_MODULE,
+                |path| unsafe { Module::deserialize_file(engine, path) },
+                || {
+                    *compiled = true;
+                    Module::new(engine, EMPTY_MODULE)
+                },
+                |module| module.serialize(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn compiled_modules_are_reused() {
+        let engine = Engine::default();
+        let cache = cache(&engine, "reuse");
+
+        let mut compiled = false;
+        load(&cache, &engine, &mut compiled);
+        assert!(compiled);
+
+        let mut compiled = false;
+        load(&cache, &engine, &mut compiled);
+        assert!(!compiled);
+    }
+
+    #[test]
+    fn concurrent_stores_of_an_entry_do_not_clash() {
+        let engine = Engine::default();
+        let cache = cache(&engine, "concurrent");
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let mut compiled = false;
+                    load(&cache, &engine, &mut compiled);
+                });
+            }
+        });
+
+        let entries: Vec<_> = std::fs::read_dir(&cache.dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert!(cache.path(EMPTY_MODULE).exists());
+    }
+
+    #[test]
+    fn broken_entries_are_compiled_again() {
+        let engine = Engine::default();
+        let cache = cache(&engine, "broken");
+        std::fs::create_dir_all(&cache.dir).unwrap();
+        std::fs::write(cache.path(EMPTY_MODULE), b"not a module").unwrap();
+
+        let mut compiled = false;
+        load(&cache, &engine, &mut compiled);
+        assert!(compiled);
+    }
+
+    #[test]
+    fn flush_keeps_stores_in_progress() {
+        let engine = Engine::default();
+        let cache = cache(&engine, "flush");
+        let mut compiled = false;
+        load(&cache, &engine, &mut compiled);
+
+        let entry = cache.path(EMPTY_MODULE);
+        let in_progress = tempfile::NamedTempFile::new_in(&cache.dir).unwrap();
+
+        let options = EngineOptions {
+            module_cache_dir: cache.dir.parent().map(Path::to_path_buf),
+            ..Default::default()
+        };
+        ModuleCache::flush(&options).unwrap();
+
+        assert!(entry.exists());
+        assert!(in_progress.path().exists());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::{ActionCapabilities, RuntimeError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+use wasmtime_wasi_nn::backend;
+use wasmtime_wasi_nn::wit::{ExecutionTarget, GraphEncoding};
+use wasmtime_wasi_nn::{Backend, Graph, GraphRegistry, Registry};
+
+/// Encoding of the graphs of an action when `nn_graph_encoding` is not set.
+pub const DEFAULT_GRAPH_ENCODING: &str = "pytorch";
+
+/// Parses the `nn_graph_encoding` annotation of the action.
+pub fn graph_encoding(capabilities: &ActionCapabilities) -> anyhow::Result<GraphEncoding> {
+    let encoding = capabilities
+        .nn_graph_encoding
+        .as_deref()
+        .unwrap_or(DEFAULT_GRAPH_ENCODING);
+
+    match encoding.to_ascii_lowercase().as_str() {
+        "openvino" => Ok(GraphEncoding::Openvino),
+        "onnx" => Ok(GraphEncoding::Onnx),
+        "tensorflow" => Ok(GraphEncoding::Tensorflow),
+        "pytorch" => Ok(GraphEncoding::Pytorch),
+        "tensorflowlite" => Ok(GraphEncoding::Tensorflowlite),
+        "ggml" => Ok(GraphEncoding::Ggml),
+        "autodetect" => Ok(GraphEncoding::Autodetect),
+        _ => Err(anyhow::anyhow!("Unknown graph encoding {}", encoding)),
+    }
+}
+
+/// Builds a graph from the bytes of a model, with the first backend that supports `encoding`.
+pub fn load_graph(model: &[u8], encoding: GraphEncoding) -> anyhow::Result<Graph> {
+    let mut backends: Vec<Backend> = backend::list();
+    let backend = backends
+        .iter_mut()
+        .find(|backend| backend.encoding() == encoding)
+        .ok_or_else(|| anyhow::anyhow!("No wasi-nn backend for {:?} graphs", encoding))?;
+
+    Ok(backend.load(&[model], ExecutionTarget::Cpu)?)
+}
+
+/// The graphs loaded into the host wasi-nn registry for each container, by container id and
+/// graph name. Guests get them with `load_by_name` instead of building them from model bytes,
+/// so a model is loaded once per container rather than once per activation.
+#[derive(Clone)]
+pub struct ContainerGraphs {
+    containers: Arc<Mutex<HashMap<String, Graphs>>>,
+    max_loaded: usize,
+}
+
+/// The graphs of one container: those of its `nn_graphs` annotation, kept until the container
+/// is destroyed, and those loaded by its activations, of which only the last `max_loaded` are
+/// kept.
+#[derive(Default)]
+struct Graphs {
+    graphs: HashMap<String, Graph>,
+    loaded: VecDeque<String>,
+}
+
+impl ContainerGraphs {
+    /// Containers keep at most `max_loaded` of the graphs loaded by their activations.
+    pub fn new(max_loaded: usize) -> Self {
+        Self {
+            containers: Arc::default(),
+            // The graph an activation just loaded must stay until its store gets the registry
+            max_loaded: max_loaded.max(1),
+        }
+    }
+
+    /// Adds the graph `name` to the container, built by `load` unless the container has it already.
+    /// Returns whether the graph was loaded. The oldest graph loaded this way is dropped once the
+    /// container has too many, and the graph is dropped if the container was destroyed meanwhile.
+    pub fn load(
+        &self,
+        container_id: &str,
+        name: &str,
+        load: impl FnOnce() -> anyhow::Result<Graph>,
+    ) -> anyhow::Result<bool> {
+        if self.contains(container_id, name) {
+            debug!(graph = %name, "Graph already loaded");
+            return Ok(false);
+        }
+
+        // The lock is not held while loading, so that loading a large model does not block the
+        // activations of other containers. Concurrent activations may load the same graph, and
+        // the first one to finish is kept.
+        info!(graph = %name, "Loading graph into the wasi-nn registry...");
+        let graph = load()?;
+
+        let mut containers = self.containers.lock().unwrap();
+        let container = containers.get_mut(container_id).ok_or_else(|| {
+            RuntimeError::internal(format!(
+                "The container was destroyed while loading graph {}",
+                name
+            ))
+        })?;
+        if container.graphs.contains_key(name) {
+            return Ok(true);
+        }
+
+        container.graphs.insert(name.to_owned(), graph);
+        container.loaded.push_back(name.to_owned());
+        while container.loaded.len() > self.max_loaded {
+            if let Some(oldest) = container.loaded.pop_front() {
+                debug!(graph = %oldest, "Dropping graph from the wasi-nn registry");
+                container.graphs.remove(&oldest);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replaces the graphs of the container with the models of its `nn_graphs` annotation,
+    /// whose bytes are returned by `fetch` given their URL. The container is left without
+    /// graphs if any of them fails to load.
+    pub fn preload<M: AsRef<[u8]>>(
+        &self,
+        container_id: &str,
+        capabilities: &ActionCapabilities,
+        fetch: impl Fn(&str) -> anyhow::Result<M>,
+    ) -> anyhow::Result<()> {
+        self.remove(container_id);
+        let encoding = graph_encoding(capabilities)?;
+
+        let mut graphs = HashMap::new();
+        for (name, url) in capabilities.nn_graphs.iter().flatten() {
+            info!(graph = %name, "Loading graph into the wasi-nn registry...");
+            let graph = fetch(url)
+                .and_then(|model| load_graph(model.as_ref(), encoding))
+                .map_err(|err| {
+                    err.context(format!("Failed to load graph {} from {}", name, url))
+                })?;
+            graphs.insert(name.clone(), graph);
+        }
+
+        let graphs = Graphs {
+            graphs,
+            loaded: VecDeque::new(),
+        };
+        self.containers
+            .lock()
+            .unwrap()
+            .insert(container_id.to_owned(), graphs);
+        Ok(())
+    }
+
+    pub fn contains(&self, container_id: &str, name: &str) -> bool {
+        let containers = self.containers.lock().unwrap();
+        containers
+            .get(container_id)
+            .is_some_and(|container| container.graphs.contains_key(name))
+    }
+
+    /// The registry for a store of the container. Graphs are reference counted, so it shares
+    /// them with the container instead of copying them.
+    pub fn registry(&self, container_id: &str) -> Registry {
+        let containers = self.containers.lock().unwrap();
+        let graphs = containers
+            .get(container_id)
+            .map(|container| container.graphs.clone())
+            .unwrap_or_default();
+        ContainerRegistry(graphs).into()
+    }
+
+    /// Drops the graphs of a destroyed container.
+    pub fn remove(&self, container_id: &str) {
+        self.containers.lock().unwrap().remove(container_id);
+    }
+}
+
+/// The graphs of a container, as seen by the wasi-nn context of one store.
+struct ContainerRegistry(HashMap<String, Graph>);
+
+impl GraphRegistry for ContainerRegistry {
+    fn get(&self, name: &str) -> Option<&Graph> {
+        self.0.get(name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Graph> {
+        self.0.get_mut(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActivationResponseStatus;
+    use wasmtime_wasi_nn::backend::{BackendError, BackendGraph};
+    use wasmtime_wasi_nn::ExecutionContext;
+
+    struct FakeGraph;
+
+    impl BackendGraph for FakeGraph {
+        fn init_execution_context(&self) -> Result<ExecutionContext, BackendError> {
+            Err(BackendError::BackendAccess(anyhow::anyhow!("fake graphs cannot be executed")))
+        }
+    }
+
+    fn fake_graph() -> anyhow::Result<Graph> {
+        Ok(Graph::from(Box::new(FakeGraph) as Box<dyn BackendGraph>))
+    }
+
+    /// Graphs of containers initialized without `nn_graphs`.
+    fn containers(container_ids: &[&str], max_loaded: usize) -> ContainerGraphs {
+        let graphs = ContainerGraphs::new(max_loaded);
+        for container_id in container_ids {
+            let fetch = |_: &str| -> anyhow::Result<Vec<u8>> { unreachable!() };
+            graphs
+                .preload(container_id, &ActionCapabilities::default(), fetch)
+                .unwrap();
+        }
+        graphs
+    }
+
+    #[test]
+    fn graphs_are_loaded_once_per_container() {
+        let graphs = containers(&["a", "b"], 8);
+        assert!(graphs.load("a", "resnet", fake_graph).unwrap());
+        assert!(!graphs.load("a", "resnet", || panic!("loaded twice")).unwrap());
+        assert!(graphs.load("b", "resnet", fake_graph).unwrap());
+
+        assert!(graphs.registry("a").get("resnet").is_some());
+        assert!(graphs.registry("a").get("vgg").is_none());
+
+        graphs.remove("a");
+        assert!(!graphs.contains("a", "resnet"));
+        assert!(graphs.contains("b", "resnet"));
+    }
+
+    #[test]
+    fn failed_loads_are_not_kept() {
+        let graphs = containers(&["a"], 8);
+        assert!(graphs.load("a", "resnet", || Err(anyhow::anyhow!("404"))).is_err());
+        assert!(!graphs.contains("a", "resnet"));
+    }
+
+    #[test]
+    fn test_the_oldest_loaded_graphs_are_dropped() {
+        let graphs = containers(&["a"], 2);
+        for name in ["resnet", "vgg", "alexnet"] {
+            assert!(graphs.load("a", name, fake_graph).unwrap());
+        }
+
+        assert!(!graphs.contains("a", "resnet"));
+        assert!(graphs.contains("a", "vgg"));
+        assert!(graphs.contains("a", "alexnet"));
+    }
+
+    #[test]
+    fn test_loads_into_destroyed_containers_are_dropped() {
+        let graphs = containers(&["a"], 8);
+
+        let loaded = graphs.load("a", "resnet", || {
+            graphs.remove("a");
+            fake_graph()
+        });
+
+        let err = RuntimeError::from(loaded.unwrap_err());
+        assert_eq!(err.status(), ActivationResponseStatus::WhiskInternalError);
+        assert!(!graphs.contains("a", "resnet"));
+    }
+
+    #[test]
+    fn graph_encoding_defaults_to_pytorch() {
+        let mut capabilities = ActionCapabilities::default();
+        assert_eq!(graph_encoding(&capabilities).unwrap(), GraphEncoding::Pytorch);
+
+        capabilities.nn_graph_encoding = Some("ONNX".to_owned());
+        assert_eq!(graph_encoding(&capabilities).unwrap(), GraphEncoding::Onnx);
+
+        capabilities.nn_graph_encoding = Some("caffe".to_owned());
+        assert!(graph_encoding(&capabilities).is_err());
+    }
+}
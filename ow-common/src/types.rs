@@ -1,12 +1,17 @@
+use crate::abi::AbiKind;
+use crate::cache::CacheInfo;
+use crate::{util, RuntimeError};
 use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, fmt::Debug};
 
-#[derive(PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ActivationResponseStatus {
     Success = 0,
     ApplicationError = 1,
-    // ActionDeveloperError = 2,
-    // WhiskInternalError = 3,
+    ActionDeveloperError = 2,
+    WhiskInternalError = 3,
 }
 
 impl Serialize for ActivationResponseStatus {
@@ -19,8 +24,8 @@ impl Serialize for ActivationResponseStatus {
         match self {
             Success => serializer.serialize_str("success"),
             ApplicationError => serializer.serialize_str("application error"),
-            // ActionDeveloperError => serializer.serialize_str("action developer error"),
-            // WhiskInternalError => serializer.serialize_str("whisk internal error"),
+            ActionDeveloperError => serializer.serialize_str("action developer error"),
+            WhiskInternalError => serializer.serialize_str("whisk internal error"),
         }
     }
 }
@@ -31,11 +36,18 @@ pub struct ActivationResponse {
     status_code: u8,
     success: bool,
     result: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fuel_consumed: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    logs: Vec<String>,
 }
 
 impl ActivationResponse {
-    pub fn new(result: Result<serde_json::Value, serde_json::Value>) -> Self {
-        match result {
+    pub fn new(outcome: ActivationOutcome) -> Self {
+        let fuel_consumed = outcome.fuel_consumed;
+        let logs = outcome.logs;
+
+        match outcome.result {
             Ok(ok) => {
                 let status = ActivationResponseStatus::Success;
                 Self {
@@ -43,6 +55,8 @@ impl ActivationResponse {
                     status_code: status.clone() as u8,
                     status,
                     result: ok,
+                    fuel_consumed,
+                    logs,
                 }
             }
             Err(err) => {
@@ -52,10 +66,81 @@ impl ActivationResponse {
                     status_code: status.clone() as u8,
                     status,
                     result: err,
+                    fuel_consumed,
+                    logs,
                 }
             }
         }
     }
+
+    pub fn status(&self) -> &ActivationResponseStatus {
+        &self.status
+    }
+
+    /// Builds the response of an activation that failed instead of producing a result, with the
+    /// fuel the guest consumed and the logs it wrote before it failed.
+    pub fn error(failure: ActivationFailure) -> Self {
+        let status = failure.error.status();
+        Self {
+            success: false,
+            status_code: status.clone() as u8,
+            status,
+            result: serde_json::json!({ "error": failure.error.to_string() }),
+            fuel_consumed: failure.fuel_consumed,
+            logs: failure.logs,
+        }
+    }
+}
+
+/// The result of running an activation, along with what the guest consumed and logged to produce it.
+#[derive(Debug)]
+pub struct ActivationOutcome {
+    pub result: Result<serde_json::Value, serde_json::Value>,
+    pub fuel_consumed: Option<u64>,
+    /// Formatted stdout and stderr lines of the guest.
+    pub logs: Vec<String>,
+}
+
+/// An activation that could not produce a result, along with what the guest consumed and logged
+/// before it failed.
+#[derive(Debug)]
+pub struct ActivationFailure {
+    pub error: RuntimeError,
+    pub fuel_consumed: Option<u64>,
+    /// Formatted stdout and stderr lines of the guest.
+    pub logs: Vec<String>,
+}
+
+impl ActivationFailure {
+    pub fn new(error: impl Into<RuntimeError>, fuel_consumed: Option<u64>, logs: Vec<String>) -> Self {
+        Self {
+            error: error.into(),
+            fuel_consumed,
+            logs,
+        }
+    }
+}
+
+impl From<RuntimeError> for ActivationFailure {
+    fn from(error: RuntimeError) -> Self {
+        Self::new(error, None, Vec::new())
+    }
+}
+
+impl From<anyhow::Error> for ActivationFailure {
+    fn from(error: anyhow::Error) -> Self {
+        Self::new(error, None, Vec::new())
+    }
+}
+
+impl From<Result<serde_json::Value, serde_json::Value>> for ActivationOutcome {
+    fn from(result: Result<serde_json::Value, serde_json::Value>) -> Self {
+        Self {
+            result,
+            fuel_consumed: None,
+            logs: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -73,10 +158,33 @@ pub struct ActivationInitInner {
     pub annotations: ActionCapabilities,
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct ActionCapabilities {
     pub dir: Option<String>,
+    pub dir_readonly: Option<bool>,
+    pub dir_guest_path: Option<String>,
     pub net_access: Option<bool>,
+    pub net_allow: Option<Vec<String>>,
+    pub fuel: Option<u64>,
+    pub memory_limit_mb: Option<u64>,
+    /// Number of elements a guest table can grow to, 100 000 if not set.
+    pub table_elements_limit: Option<u64>,
+    /// Runtime that hosts the action, e.g. `wasmtime_component`. Chosen by the executor from the
+    /// kind of artifact if not set.
+    pub runtime: Option<String>,
+    /// Calling convention the action was built with: `memory`, `args` or `stdio` for modules,
+    /// and `component` for components. Defaults to `memory` for modules and `component` for
+    /// components.
+    pub abi: Option<String>,
+    /// Models loaded into the host wasi-nn registry when the container is initialized, by graph
+    /// name and URL. Guests get them with `load_by_name`.
+    pub nn_graphs: Option<HashMap<String, String>>,
+    /// Whether the guest loads its graphs by name. The models named by the `model` and `models`
+    /// parameters are then loaded into the host wasi-nn registry under their URL, once per
+    /// container, instead of being copied into the guest on every activation.
+    pub nn_load_by_name: Option<bool>,
+    /// Encoding of the models above, `pytorch` if not set.
+    pub nn_graph_encoding: Option<String>,
 }
 
 impl Debug for ActivationInitInner {
@@ -116,6 +224,65 @@ where
 pub struct WasmAction<M> {
     pub module: M,
     pub capabilities: ActionCapabilities,
+    pub abi: AbiKind,
+    pub env: HashMap<String, String>,
+    /// Hash of the uploaded artifact, which keys the instance cache.
+    pub module_hash: u64,
+    // Milliseconds since the Unix epoch
+    last_used: AtomicU64,
+}
+
+impl<M> WasmAction<M> {
+    pub fn new(
+        module: M,
+        capabilities: ActionCapabilities,
+        abi: AbiKind,
+        env: HashMap<String, String>,
+        module_hash: u64,
+    ) -> Self {
+        let action = Self {
+            module,
+            capabilities,
+            abi,
+            env,
+            module_hash,
+            last_used: AtomicU64::new(0),
+        };
+        action.touch();
+        action
+    }
+
+    /// Records that the action is being used now.
+    pub fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.last_used.store(now, Ordering::Relaxed);
+    }
+
+    pub fn info(&self, container_id: &str) -> ContainerInfo {
+        let last_used = UNIX_EPOCH + Duration::from_millis(self.last_used.load(Ordering::Relaxed));
+
+        ContainerInfo {
+            container_id: container_id.to_owned(),
+            capabilities: self.capabilities.clone(),
+            abi: self.abi,
+            module_hash: util::format_hash(self.module_hash),
+            last_used: humantime::format_rfc3339_millis(last_used).to_string(),
+        }
+    }
+}
+
+/// An initialized container, as reported by the runtimes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerInfo {
+    pub container_id: String,
+    pub capabilities: ActionCapabilities,
+    pub abi: AbiKind,
+    pub module_hash: String,
+    /// When the container was last initialized or run, in RFC 3339.
+    pub last_used: String,
 }
 
 pub trait WasmRuntime: Clone {
@@ -123,14 +290,25 @@ pub trait WasmRuntime: Clone {
         &self,
         container_id: String,
         capabilities: ActionCapabilities,
+        env: HashMap<String, String>,
         module: Vec<u8>,
-    ) -> anyhow::Result<()>;
+    ) -> Result<(), RuntimeError>;
 
     fn run(
         &self,
         container_id: &str,
-        parameters: serde_json::Value,
-    ) -> Result<Result<serde_json::Value, serde_json::Value>, anyhow::Error>;
+        context: ActivationContext,
+    ) -> Result<ActivationOutcome, ActivationFailure>;
 
     fn destroy(&self, container_id: &str);
+
+    /// The containers initialized in this runtime.
+    fn containers(&self) -> Vec<ContainerInfo>;
+
+    /// The contents of the caches of this runtime.
+    fn caches(&self) -> Vec<CacheInfo>;
+
+    /// Removes the entries for `key` from the cache called `cache`, where `key` is formatted
+    /// as in `caches`. Returns how many entries were removed.
+    fn evict(&self, cache: &str, key: &str) -> usize;
 }
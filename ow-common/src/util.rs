@@ -1,17 +1,19 @@
 use std::io::Cursor;
+use tracing::debug;
 
-#[inline(always)]
+#[tracing::instrument(skip_all)]
 pub fn b64_decode(b64_string: String) -> anyhow::Result<Vec<u8>> {
     let time = std::time::Instant::now();
     let module_bytes: Vec<u8> = base64::decode(b64_string)?;
-    println!("base64 decoding took {} ms", time.elapsed().as_millis());
+    debug!("base64 decoding took {} ms", time.elapsed().as_millis());
     Ok(module_bytes)
 }
 
+#[tracing::instrument(skip_all)]
 pub fn unzip(bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
     let mut target = Cursor::new(Vec::with_capacity(bytes.len()));
     let cursor = Cursor::new(bytes);
-    let mut archive = zip::ZipArchive::new(cursor).unwrap();
+    let mut archive = zip::ZipArchive::new(cursor)?;
 
     let mut file = archive.by_index(0)?;
 
@@ -19,3 +21,57 @@ pub fn unzip(bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
 
     Ok(target.into_inner())
 }
+
+/// Follows the OpenWhisk convention that a result with a top-level `error` field is an
+/// application error, so that guests can fail without trapping.
+pub fn application_result(result: serde_json::Value) -> Result<serde_json::Value, serde_json::Value> {
+    if result.get("error").is_some() {
+        Err(result)
+    } else {
+        Ok(result)
+    }
+}
+
+/// Formats the hash of an action artifact the way it is shown to operators.
+pub fn format_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Adds the timings of the runtime to the `field` object of a guest result. Results that are
+/// neither objects nor null are left as they are, since indexing them would panic.
+pub fn insert_metrics<'a>(
+    result: &mut serde_json::Value,
+    field: &str,
+    metrics: impl IntoIterator<Item = (&'a str, f64)>,
+) {
+    if result.is_null() {
+        *result = serde_json::json!({});
+    }
+    let Some(object) = result.as_object_mut() else {
+        return;
+    };
+
+    let entry = object.entry(field).or_insert_with(|| serde_json::json!({}));
+    if let Some(entry) = entry.as_object_mut() {
+        for (key, value) in metrics {
+            entry.insert(key.to_owned(), serde_json::json!(value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_only_added_to_objects() {
+        let mut result = serde_json::json!({ "metrics": { "loss": 0.5 } });
+        insert_metrics(&mut result, "metrics", [("func_time", 1.5)]);
+        assert_eq!(result, serde_json::json!({ "metrics": { "loss": 0.5, "func_time": 1.5 } }));
+
+        // A guest that returns an array must not make the runtime panic
+        let mut result = serde_json::json!([1, 2]);
+        insert_metrics(&mut result, "metrics", [("func_time", 1.5)]);
+        assert_eq!(result, serde_json::json!([1, 2]));
+    }
+}
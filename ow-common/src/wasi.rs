@@ -0,0 +1,486 @@
+use crate::engine::EngineOptions;
+use crate::{ActionCapabilities, ActivationContext, RuntimeError};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use wasmtime::component::Linker;
+use wasmtime_wasi::bindings::{self, sync};
+use wasmtime_wasi::{DirPerms, FilePerms, SocketAddrUse, WasiCtxBuilder, WasiImpl, WasiView};
+
+/// Guest path at which `ActionCapabilities::dir` is mounted when `dir_guest_path` is not set.
+pub const DEFAULT_GUEST_DIR: &str = ".";
+
+/// Resolves the `dir` annotation of the action, if any, to a canonical path inside the
+/// `guest_dir_base` of the executor. Relative paths are taken from the base directory.
+/// Actions with a `dir` are rejected if the executor has no base directory.
+pub fn guest_dir(
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+) -> Result<Option<PathBuf>, RuntimeError> {
+    let Some(dir) = &capabilities.dir else {
+        return Ok(None);
+    };
+    let Some(base) = &options.guest_dir_base else {
+        return Err(RuntimeError::developer(
+            "This executor does not allow actions to access host directories",
+        ));
+    };
+
+    let base = base.canonicalize().map_err(|err| {
+        RuntimeError::internal(format!(
+            "Failed to open the base directory {}: {}",
+            base.display(),
+            err
+        ))
+    })?;
+    let host_dir = base.join(dir).canonicalize().map_err(|err| {
+        RuntimeError::developer(format!("Failed to open directory {}: {}", dir, err))
+    })?;
+    if !host_dir.starts_with(&base) || !host_dir.is_dir() {
+        return Err(RuntimeError::developer(format!(
+            "The directory {} is not a directory under {}",
+            dir,
+            base.display()
+        )));
+    }
+
+    Ok(Some(host_dir))
+}
+
+/// Preopens the directory from the `dir` annotation of the action, if any.
+/// The directory is read-only unless `dir_readonly` is set to false.
+pub fn preopen_dir(
+    builder: &mut WasiCtxBuilder,
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+) -> anyhow::Result<()> {
+    let Some(host_dir) = guest_dir(options, capabilities)? else {
+        return Ok(());
+    };
+
+    let guest_dir = capabilities
+        .dir_guest_path
+        .as_deref()
+        .unwrap_or(DEFAULT_GUEST_DIR);
+
+    let (dir_perms, file_perms) = if capabilities.dir_readonly.unwrap_or(true) {
+        (DirPerms::READ, FilePerms::READ)
+    } else {
+        (DirPerms::all(), FilePerms::all())
+    };
+
+    builder
+        .preopened_dir(&host_dir, guest_dir, dir_perms, file_perms)
+        .map_err(|err| {
+            anyhow::anyhow!(
+                "Failed to preopen directory {}: {}",
+                host_dir.display(),
+                err
+            )
+        })?;
+
+    Ok(())
+}
+
+/// Builds the environment of the guest: the `env` sent at init, followed by the `__OW_*`
+/// variables that OpenWhisk actions use to learn about the activation they are running.
+/// The `__OW_*` variables take precedence over init variables with the same name.
+pub fn guest_env(
+    init_env: &HashMap<String, String>,
+    context: &ActivationContext,
+) -> Vec<(String, String)> {
+    let mut env: BTreeMap<String, String> = init_env.clone().into_iter().collect();
+
+    let mut set = |key: &str, value: &str| {
+        env.insert(key.to_owned(), value.to_owned());
+    };
+    set("__OW_ACTIVATION_ID", &context.activation_id);
+    set("__OW_NAMESPACE", &context.namespace);
+    set("__OW_ACTION_NAME", &context.action_name);
+    set("__OW_TRANSACTION_ID", &context.transaction_id);
+    set("__OW_DEADLINE", &context.deadline.to_string());
+    if let Some(api_host) = &context.api_host {
+        set("__OW_API_HOST", api_host);
+    }
+    if let Some(api_key) = &context.api_key {
+        set("__OW_API_KEY", api_key);
+    }
+
+    env.into_iter().collect()
+}
+
+/// Destinations a guest with `net_access` may connect to, taken from the `net_allow` annotation.
+/// Each entry is a host name, an IP address or a CIDR range, optionally followed by `:port`.
+/// An action without `net_allow` may connect to any public address, but not to private,
+/// loopback or link-local ones such as the metadata service of the cloud provider.
+#[derive(Debug, Clone, Default)]
+pub struct NetPolicy {
+    allowed: Option<Vec<Destination>>,
+}
+
+#[derive(Debug, Clone)]
+struct Destination {
+    host: Host,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+enum Host {
+    /// A host name, with the addresses it had when the policy was resolved.
+    Name(String, Vec<IpAddr>),
+    /// An IP address or a CIDR range, as the network address and the prefix length.
+    Net(IpAddr, u8),
+}
+
+impl NetPolicy {
+    pub fn new(capabilities: &ActionCapabilities) -> Self {
+        let allowed = capabilities
+            .net_allow
+            .as_ref()
+            .map(|entries| entries.iter().map(|entry| parse_entry(entry)).collect());
+
+        Self { allowed }
+    }
+
+    /// Resolves the host names in the allowlist once, so that sockets are checked against the
+    /// addresses they had instead of resolving the names again on every connection.
+    pub fn resolve(mut self) -> Self {
+        for destination in self.allowed.iter_mut().flatten() {
+            if let Host::Name(name, addrs) = &mut destination.host {
+                *addrs = (name.as_str(), 0)
+                    .to_socket_addrs()
+                    .map(|resolved| resolved.map(|addr| addr.ip().to_canonical()).collect())
+                    .unwrap_or_default();
+            }
+        }
+        self
+    }
+
+    /// Returns true if the guest may send requests to `host` on `port`.
+    pub fn allows_host(&self, host: &str, port: u16) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let ip = host.parse::<IpAddr>().ok().map(|ip| ip.to_canonical());
+        let Some(allowed) = &self.allowed else {
+            return ip.is_none_or(is_public);
+        };
+
+        allowed.iter().any(|destination| {
+            destination.port.is_none_or(|p| p == port)
+                && match &destination.host {
+                    Host::Name(name, _) => name.eq_ignore_ascii_case(host),
+                    Host::Net(net, prefix) => ip.is_some_and(|ip| in_net(ip, *net, *prefix)),
+                }
+        })
+    }
+
+    /// Returns true if the guest may open a socket to `addr`. Host names in the allowlist only
+    /// match the addresses they had when the policy was resolved.
+    pub fn allows_addr(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        let Some(allowed) = &self.allowed else {
+            return is_public(ip);
+        };
+
+        allowed.iter().any(|destination| {
+            destination.port.is_none_or(|p| p == addr.port())
+                && match &destination.host {
+                    Host::Name(_, addrs) => addrs.contains(&ip),
+                    Host::Net(net, prefix) => in_net(ip, *net, *prefix),
+                }
+        })
+    }
+}
+
+fn parse_entry(entry: &str) -> Destination {
+    let (host, port) = split_port(entry.trim());
+
+    let host = match host.split_once('/') {
+        Some((net, prefix)) => match (net.parse::<IpAddr>(), prefix.parse::<u8>()) {
+            (Ok(net), Ok(prefix)) if prefix <= max_prefix(net) => Host::Net(net, prefix),
+            _ => Host::Name(host.to_owned(), Vec::new()),
+        },
+        None => match host.parse::<IpAddr>() {
+            Ok(ip) => Host::Net(ip.to_canonical(), max_prefix(ip.to_canonical())),
+            Err(_) => Host::Name(host.to_owned(), Vec::new()),
+        },
+    };
+
+    Destination { host, port }
+}
+
+fn split_port(entry: &str) -> (&str, Option<u16>) {
+    // `[::1]:8080`, `[::1]` and bare IPv6 addresses
+    if let Some(rest) = entry.strip_prefix('[') {
+        if let Some((host, port)) = rest.split_once(']') {
+            let port = port.strip_prefix(':').and_then(|port| port.parse().ok());
+            return (host, port);
+        }
+    }
+    if entry
+        .split('/')
+        .next()
+        .is_some_and(|ip| ip.parse::<IpAddr>().is_ok())
+    {
+        return (entry, None);
+    }
+
+    match entry.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (entry, None),
+        },
+        None => (entry, None),
+    }
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Returns true if `ip` is in the range of the network address `net` with `prefix` bits.
+fn in_net(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if `ip` is a public unicast address, which guests without `net_allow` may
+/// connect to. Expects IPv4-mapped addresses to be converted to IPv4 already.
+fn is_public(ip: IpAddr) -> bool {
+    let denied: &[(IpAddr, u8)] = &[
+        (IpAddr::from([0, 0, 0, 0]), 8),
+        (IpAddr::from([10, 0, 0, 0]), 8),
+        (IpAddr::from([100, 64, 0, 0]), 10),
+        (IpAddr::from([127, 0, 0, 0]), 8),
+        (IpAddr::from([169, 254, 0, 0]), 16),
+        (IpAddr::from([172, 16, 0, 0]), 12),
+        (IpAddr::from([192, 168, 0, 0]), 16),
+        (IpAddr::from([224, 0, 0, 0]), 4),
+        (IpAddr::from([240, 0, 0, 0]), 4),
+        (IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 0]), 127),
+        (IpAddr::from([0xfc00u16, 0, 0, 0, 0, 0, 0, 0]), 7),
+        (IpAddr::from([0xfe80u16, 0, 0, 0, 0, 0, 0, 0]), 10),
+        (IpAddr::from([0xff00u16, 0, 0, 0, 0, 0, 0, 0]), 8),
+    ];
+
+    !denied.iter().any(|(net, prefix)| in_net(ip, *net, *prefix))
+}
+
+/// Lets the guest open sockets and resolve names if the action has `net_access`, restricted
+/// to the destinations in its `NetPolicy`. Guests without `net_access` keep the default of
+/// the builder, which denies every address.
+pub fn allow_network(builder: &mut WasiCtxBuilder, capabilities: &ActionCapabilities) {
+    if !capabilities.net_access.unwrap_or(false) {
+        return;
+    }
+
+    let policy = NetPolicy::new(capabilities).resolve();
+    builder
+        .allow_ip_name_lookup(true)
+        .socket_addr_check(move |addr, addr_use| {
+            // Binding is always allowed, the allowlist only restricts the destinations
+            let allowed = match addr_use {
+                SocketAddrUse::TcpBind | SocketAddrUse::UdpBind => true,
+                _ => policy.allows_addr(addr),
+            };
+            Box::pin(async move { allowed })
+        });
+}
+
+/// Adds the WASI interfaces to a component linker. `wasi:sockets` is only added for actions
+/// with `net_access`, so that components importing it fail to initialize otherwise.
+pub fn add_to_linker_sync<T: WasiView>(
+    linker: &mut Linker<T>,
+    net_access: bool,
+) -> anyhow::Result<()> {
+    if net_access {
+        return wasmtime_wasi::add_to_linker_sync(linker);
+    }
+
+    // Same as `wasmtime_wasi::add_to_linker_sync`, without the `wasi:sockets` interfaces
+    let options = sync::LinkOptions::default();
+    let l = linker;
+    let closure = type_annotate::<T, _>(|t| WasiImpl(t));
+
+    bindings::clocks::wall_clock::add_to_linker_get_host(l, closure)?;
+    bindings::clocks::monotonic_clock::add_to_linker_get_host(l, closure)?;
+    sync::filesystem::types::add_to_linker_get_host(l, closure)?;
+    bindings::filesystem::preopens::add_to_linker_get_host(l, closure)?;
+    bindings::io::error::add_to_linker_get_host(l, closure)?;
+    sync::io::poll::add_to_linker_get_host(l, closure)?;
+    sync::io::streams::add_to_linker_get_host(l, closure)?;
+    bindings::random::random::add_to_linker_get_host(l, closure)?;
+    bindings::random::insecure::add_to_linker_get_host(l, closure)?;
+    bindings::random::insecure_seed::add_to_linker_get_host(l, closure)?;
+    bindings::cli::exit::add_to_linker_get_host(l, &options.into(), closure)?;
+    bindings::cli::environment::add_to_linker_get_host(l, closure)?;
+    bindings::cli::stdin::add_to_linker_get_host(l, closure)?;
+    bindings::cli::stdout::add_to_linker_get_host(l, closure)?;
+    bindings::cli::stderr::add_to_linker_get_host(l, closure)?;
+    bindings::cli::terminal_input::add_to_linker_get_host(l, closure)?;
+    bindings::cli::terminal_output::add_to_linker_get_host(l, closure)?;
+    bindings::cli::terminal_stdin::add_to_linker_get_host(l, closure)?;
+    bindings::cli::terminal_stdout::add_to_linker_get_host(l, closure)?;
+    bindings::cli::terminal_stderr::add_to_linker_get_host(l, closure)?;
+    Ok(())
+}
+
+// Helps rustc infer the type of the closure, as done in wasmtime-wasi
+fn type_annotate<T: WasiView, F>(val: F) -> F
+where
+    F: Fn(&mut T) -> WasiImpl<&mut T>,
+{
+    val
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(entries: &[&str]) -> NetPolicy {
+        NetPolicy::new(&ActionCapabilities {
+            net_access: Some(true),
+            net_allow: Some(entries.iter().map(|entry| entry.to_string()).collect()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_guest_dirs_are_confined_to_the_base_directory() {
+        let base = std::env::temp_dir().join(format!("ow-guest-dirs-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("tenant")).unwrap();
+        let options = |base: Option<PathBuf>| EngineOptions {
+            guest_dir_base: base,
+            ..Default::default()
+        };
+        let dir = |dir: &str| ActionCapabilities {
+            dir: Some(dir.to_owned()),
+            ..Default::default()
+        };
+        let with_base = options(Some(base.clone()));
+        let is_developer_error = |result: Result<_, RuntimeError>| {
+            matches!(result, Err(RuntimeError::ActionDeveloper(_)))
+        };
+
+        let expected = base.join("tenant").canonicalize().unwrap();
+        assert_eq!(
+            guest_dir(&with_base, &dir("tenant")).unwrap(),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            guest_dir(&with_base, &dir(expected.to_str().unwrap())).unwrap(),
+            Some(expected)
+        );
+        assert_eq!(
+            guest_dir(&with_base, &ActionCapabilities::default()).unwrap(),
+            None
+        );
+        assert!(is_developer_error(guest_dir(&with_base, &dir("/"))));
+        assert!(is_developer_error(guest_dir(
+            &with_base,
+            &dir("tenant/../..")
+        )));
+        assert!(is_developer_error(guest_dir(&with_base, &dir("missing"))));
+        assert!(is_developer_error(guest_dir(
+            &options(None),
+            &dir("tenant")
+        )));
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn guest_env_overrides_init_variables() {
+        let init_env = HashMap::from([
+            ("DB_URL".to_owned(), "postgres://db".to_owned()),
+            ("__OW_NAMESPACE".to_owned(), "spoofed".to_owned()),
+        ]);
+        let context = ActivationContext {
+            value: serde_json::Value::Null,
+            namespace: "guest".to_owned(),
+            action_name: "/guest/hello".to_owned(),
+            api_host: Some("https://openwhisk".to_owned()),
+            api_key: None,
+            activation_id: "activation".to_owned(),
+            transaction_id: "transaction".to_owned(),
+            deadline: 1700000000000,
+        };
+
+        let env: HashMap<_, _> = guest_env(&init_env, &context).into_iter().collect();
+
+        assert_eq!(env["DB_URL"], "postgres://db");
+        assert_eq!(env["__OW_NAMESPACE"], "guest");
+        assert_eq!(env["__OW_DEADLINE"], "1700000000000");
+        assert_eq!(env["__OW_API_HOST"], "https://openwhisk");
+        assert!(!env.contains_key("__OW_API_KEY"));
+    }
+
+    #[test]
+    fn allowlist_matches_hosts_and_ports() {
+        let policy = policy(&["api.internal:8080", "storage.internal"]);
+
+        assert!(policy.allows_host("api.internal", 8080));
+        assert!(!policy.allows_host("api.internal", 80));
+        assert!(policy.allows_host("storage.internal", 443));
+        assert!(!policy.allows_host("example.com", 443));
+    }
+
+    #[test]
+    fn allowlist_matches_ip_addresses() {
+        let policy = policy(&["10.0.0.1:5432", "[::1]"]);
+
+        assert!(policy.allows_addr("10.0.0.1:5432".parse().unwrap()));
+        assert!(!policy.allows_addr("10.0.0.2:5432".parse().unwrap()));
+        assert!(policy.allows_addr("[::1]:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_allowlist_matches_cidr_ranges() {
+        let policy = policy(&["10.0.0.0/8", "[fd00::/8]:443"]);
+
+        assert!(policy.allows_addr("10.20.30.40:5432".parse().unwrap()));
+        assert!(policy.allows_addr("[::ffff:10.1.2.3]:80".parse().unwrap()));
+        assert!(!policy.allows_addr("11.0.0.1:80".parse().unwrap()));
+        assert!(policy.allows_addr("[fd00::1]:443".parse().unwrap()));
+        assert!(!policy.allows_addr("[fd00::1]:80".parse().unwrap()));
+        assert!(policy.allows_host("10.0.0.7", 80));
+    }
+
+    #[test]
+    fn test_host_names_match_sockets_only_once_resolved() {
+        let policy = policy(&["localhost:8080"]);
+        assert!(!policy.allows_addr("127.0.0.1:8080".parse().unwrap()));
+
+        let policy = policy.resolve();
+        assert!(policy.allows_addr("127.0.0.1:8080".parse().unwrap()));
+        assert!(!policy.allows_addr("127.0.0.1:22".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_missing_allowlist_allows_only_public_addresses() {
+        let policy = NetPolicy::default();
+
+        assert!(policy.allows_host("example.com", 80));
+        assert!(policy.allows_addr("93.184.216.34:443".parse().unwrap()));
+        assert!(policy.allows_addr("[2606:2800::1]:443".parse().unwrap()));
+        assert!(!policy.allows_addr("192.168.1.1:22".parse().unwrap()));
+        assert!(!policy.allows_addr("127.0.0.1:80".parse().unwrap()));
+        assert!(!policy.allows_addr("169.254.169.254:80".parse().unwrap()));
+        assert!(!policy.allows_addr("[::1]:80".parse().unwrap()));
+        assert!(!policy.allows_addr("[::ffff:10.0.0.1]:80".parse().unwrap()));
+        assert!(!policy.allows_addr("[fe80::1]:80".parse().unwrap()));
+        assert!(!policy.allows_host("169.254.169.254", 80));
+        assert!(!policy.allows_host("[fd00:ec2::254]", 80));
+    }
+}
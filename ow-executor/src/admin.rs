@@ -0,0 +1,99 @@
+use crate::router::Router;
+use ow_common::metrics::metrics;
+use ow_common::WasmRuntime;
+use serde::{Deserialize, Serialize};
+use tide::{Body, Request, Response, StatusCode};
+use tracing::info;
+
+/// Liveness probe: the executor answers requests.
+pub async fn health(_req: Request<Router>) -> tide::Result<Response> {
+    json(StatusCode::Ok, &serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness probe: the executor accepts new containers.
+pub async fn ready(req: Request<Router>) -> tide::Result<Response> {
+    if req.state().is_ready() {
+        json(StatusCode::Ok, &serde_json::json!({ "status": "ready" }))
+    } else {
+        json(StatusCode::ServiceUnavailable, &serde_json::json!({ "status": "not ready" }))
+    }
+}
+
+#[derive(Serialize)]
+struct RoutedContainer {
+    runtime: Option<&'static str>,
+    #[serde(flatten)]
+    info: ow_common::ContainerInfo,
+}
+
+/// Lists the initialized containers with their runtime.
+pub async fn containers(req: Request<Router>) -> tide::Result<Response> {
+    let router = req.state();
+
+    let mut containers: Vec<RoutedContainer> = router
+        .containers()
+        .into_iter()
+        .map(|info| RoutedContainer {
+            runtime: router.runtime_of(&info.container_id).map(|kind| kind.name()),
+            info,
+        })
+        .collect();
+    containers.sort_by(|a, b| a.info.container_id.cmp(&b.info.container_id));
+
+    json(StatusCode::Ok, &containers)
+}
+
+/// Destroys a container, as if OpenWhisk had removed it.
+pub async fn evict_container(req: Request<Router>) -> tide::Result<StatusCode> {
+    let container_id = req.param("container_id")?;
+    let router = req.state();
+
+    if router.runtime_of(container_id).is_none() {
+        return Ok(StatusCode::NotFound);
+    }
+
+    info!("Evicting container {}", container_id);
+    router.destroy(container_id);
+
+    Ok(StatusCode::NoContent)
+}
+
+/// Lists the caches of every runtime with their entries.
+pub async fn caches(req: Request<Router>) -> tide::Result<Response> {
+    json(StatusCode::Ok, &req.state().caches())
+}
+
+#[derive(Deserialize)]
+struct EvictQuery {
+    key: String,
+}
+
+/// Removes an entry from a cache. The key is a query parameter, since model keys are URLs.
+pub async fn evict_cache_entry(req: Request<Router>) -> tide::Result<Response> {
+    let cache = format!("{}/{}", req.param("runtime")?, req.param("cache")?);
+    let EvictQuery { key } = req.query()?;
+
+    let evicted = req.state().evict(&cache, &key);
+    info!("Evicted {} entries for {} from {}", evicted, key, cache);
+
+    let status = if evicted > 0 { StatusCode::Ok } else { StatusCode::NotFound };
+    json(status, &serde_json::json!({ "evicted": evicted }))
+}
+
+/// Prometheus metrics of the activations, inits, caches and downloads of every runtime.
+pub async fn prometheus(req: Request<Router>) -> tide::Result<Response> {
+    let text = metrics().encode(&req.state().caches())?;
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("text/plain; version=0.0.4");
+    res.set_body(text);
+
+    Ok(res)
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> tide::Result<Response> {
+    let mut res = Response::new(status);
+    res.set_body(Body::from_json(body)?);
+
+    Ok(res)
+}
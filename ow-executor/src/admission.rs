@@ -0,0 +1,253 @@
+use async_lock::{Semaphore, SemaphoreGuardArc};
+use dashmap::DashMap;
+use ow_common::metrics::metrics;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tide::{Body, Next, Request, Response, StatusCode};
+use tracing::warn;
+
+/// Default number of activations waiting for a slot, across all containers.
+pub const DEFAULT_MAX_QUEUED_ACTIVATIONS: usize = 64;
+/// Default time an activation waits for a slot before it is rejected.
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds on the activations an executor runs at the same time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdmissionLimits {
+    /// Activations running at the same time, across all containers.
+    pub max_activations: usize,
+    /// Activations of a single container running at the same time.
+    pub max_container_activations: usize,
+    /// Activations that wait for a slot once the limits are reached. Any other is rejected.
+    pub max_queued: usize,
+    /// How long an activation waits for a slot before it is rejected.
+    pub queue_timeout: Duration,
+}
+
+/// Why an activation was not admitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The container runs as many activations as it may, answered with 429.
+    ContainerBusy,
+    /// The executor runs as many activations as it may, answered with 503.
+    ExecutorBusy,
+}
+
+impl Rejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Rejection::ContainerBusy => StatusCode::TooManyRequests,
+            Rejection::ExecutorBusy => StatusCode::ServiceUnavailable,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self {
+            Rejection::ContainerBusy => "container_busy",
+            Rejection::ExecutorBusy => "executor_busy",
+        }
+    }
+}
+
+/// Limits the activations running at the same time, per container and for the whole executor.
+/// Activations beyond the limits wait in a bounded queue, and are rejected once it is full or
+/// they waited for longer than the queue timeout.
+#[derive(Clone)]
+pub struct Admission {
+    limits: AdmissionLimits,
+    global: Arc<Semaphore>,
+    containers: Arc<DashMap<String, Arc<Semaphore>>>,
+    running: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// Lets an activation run until it is dropped.
+pub struct Permit {
+    admission: Admission,
+    container_id: String,
+    _container: SemaphoreGuardArc,
+    _global: SemaphoreGuardArc,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.admission.running.fetch_sub(1, Ordering::SeqCst);
+        // The guard of this permit is the other owner of the semaphore
+        self.admission.forget_if_idle(&self.container_id, 2);
+    }
+}
+
+/// The activations an executor can still take, so that the invoker can schedule around it.
+#[derive(Debug, Serialize)]
+pub struct Capacity {
+    pub max_activations: usize,
+    pub max_container_activations: usize,
+    pub running: usize,
+    /// Activations that can start right away.
+    pub available: usize,
+    pub queued: usize,
+    pub max_queued: usize,
+}
+
+impl Admission {
+    pub fn new(limits: AdmissionLimits) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(limits.max_activations)),
+            limits,
+            containers: Arc::new(DashMap::new()),
+            running: Arc::new(AtomicUsize::new(0)),
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn capacity(&self) -> Capacity {
+        let running = self.running.load(Ordering::SeqCst);
+
+        Capacity {
+            max_activations: self.limits.max_activations,
+            max_container_activations: self.limits.max_container_activations,
+            running,
+            available: self.limits.max_activations.saturating_sub(running),
+            queued: self.queued.load(Ordering::SeqCst),
+            max_queued: self.limits.max_queued,
+        }
+    }
+
+    /// Waits for a slot for an activation of `container_id`, if the queue has room for it.
+    pub async fn admit(&self, container_id: &str) -> Result<Permit, Rejection> {
+        let container = self
+            .containers
+            .entry(container_id.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limits.max_container_activations)))
+            .clone();
+
+        // The container slot is always taken first, so that waiting activations never deadlock
+        let rejection = match container.try_acquire_arc() {
+            Some(container_guard) => match self.global.try_acquire_arc() {
+                Some(global_guard) => {
+                    return Ok(self.permit(container_id, container_guard, global_guard))
+                }
+                None => Rejection::ExecutorBusy,
+            },
+            None => Rejection::ContainerBusy,
+        };
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.limits.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.forget_if_idle(container_id, 2);
+            return Err(rejection);
+        }
+        let wait = async {
+            let container_guard = container.acquire_arc().await;
+            let global_guard = self.global.acquire_arc().await;
+            (container_guard, global_guard)
+        };
+        let admitted = async_std::future::timeout(self.limits.queue_timeout, wait).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        match admitted {
+            Ok((container_guard, global_guard)) => {
+                Ok(self.permit(container_id, container_guard, global_guard))
+            }
+            Err(_) => {
+                self.forget_if_idle(container_id, 2);
+                Err(rejection)
+            }
+        }
+    }
+
+    /// Forgets the semaphore of a container once no activation holds or waits for its slots,
+    /// that is when the map and `owners - 1` handles of the caller are its only owners.
+    fn forget_if_idle(&self, container_id: &str, owners: usize) {
+        self.containers
+            .remove_if(container_id, |_, semaphore| Arc::strong_count(semaphore) <= owners);
+    }
+
+    fn permit(
+        &self,
+        container_id: &str,
+        container: SemaphoreGuardArc,
+        global: SemaphoreGuardArc,
+    ) -> Permit {
+        self.running.fetch_add(1, Ordering::SeqCst);
+
+        Permit {
+            admission: self.clone(),
+            container_id: container_id.to_owned(),
+            _container: container,
+            _global: global,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Admission {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let container_id = req.param("container_id")?.to_owned();
+
+        match self.admit(&container_id).await {
+            Ok(_permit) => Ok(next.run(req).await),
+            Err(rejection) => {
+                warn!("Rejecting an activation of {}: {}", container_id, rejection.reason());
+                metrics().activation_rejected(rejection.reason());
+
+                let mut res = Response::new(rejection.status());
+                res.insert_header("Retry-After", "1");
+                res.set_body(Body::from_json(&serde_json::json!({
+                    "error": format!("The activation was rejected: {}", rejection.reason()),
+                }))?);
+                Ok(res)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_activations: usize, max_container_activations: usize) -> Admission {
+        Admission::new(AdmissionLimits {
+            max_activations,
+            max_container_activations,
+            max_queued: 1,
+            queue_timeout: Duration::from_millis(50),
+        })
+    }
+
+    #[async_std::test]
+    async fn busy_containers_and_executors_are_told_apart() {
+        let admission = admission(2, 1);
+
+        let first = admission.admit("a").await.unwrap();
+        assert_eq!(admission.admit("a").await.err(), Some(Rejection::ContainerBusy));
+
+        let _second = admission.admit("b").await.unwrap();
+        assert_eq!(admission.admit("c").await.err(), Some(Rejection::ExecutorBusy));
+        assert_eq!(admission.capacity().available, 0);
+
+        drop(first);
+        assert_eq!(admission.capacity().available, 1);
+        assert!(admission.admit("a").await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn queued_activations_run_when_a_slot_frees_up() {
+        let admission = admission(1, 1);
+        let first = admission.admit("a").await.unwrap();
+
+        let waiting = {
+            let admission = admission.clone();
+            async_std::task::spawn(async move { admission.admit("a").await.is_ok() })
+        };
+        async_std::task::sleep(Duration::from_millis(10)).await;
+        assert_eq!(admission.capacity().queued, 1);
+        // The queue is full
+        assert_eq!(admission.admit("a").await.err(), Some(Rejection::ContainerBusy));
+
+        drop(first);
+        assert!(waiting.await);
+    }
+}
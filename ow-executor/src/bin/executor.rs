@@ -1,36 +1,82 @@
-use ow_executor::core;
-
-static ADDRESS: &str = "127.0.0.1:9000";
+use async_std::prelude::FutureExt;
+use ow_executor::config::ExecutorConfig;
+use ow_executor::admission::Admission;
+use ow_executor::{admin, core, logging, shutdown};
+use ow_executor::router::{Router, RuntimeKind};
+use ow_executor::shutdown::Drain;
+use tracing::{info, warn};
 
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
+    let config = ExecutorConfig::load()?;
+    // Kept until the end of main, so that the last spans are exported
+    let _telemetry = logging::init(&config)?;
+
+    let runtime = Router::new(&config)?;
 
-    
-    #[cfg(all(feature = "wasmtime", not(feature = "wasmtime_nn"), not(feature = "wasmtime_nn_parallel"), not(feature = "wasmtime_component"), not(feature = "wasmtime_component_nn") ))]
-    let runtime = ow_wasmtime::Wasmtime::default();
+    let runtimes: Vec<&str> = RuntimeKind::available().iter().map(RuntimeKind::name).collect();
+    info!("Hosting the {} runtimes", runtimes.join(", "));
 
-    #[cfg(feature = "wasmtime_nn")]
-    let runtime = ow_wasmtime_nn::Wasmtime::default();
+    let drain = Drain::default();
+    let admission = Admission::new(config.admission.clone());
+    let mut executor = tide::with_state(runtime.clone());
 
-    #[cfg(feature = "wasmtime_nn_parallel")]
-    let runtime = ow_wasmtime_nn_parallel::Wasmtime::default();
+    executor.at("/:container_id/destroy").post(core::destroy);
+    executor.at("/:container_id/init").with(drain.clone()).post(core::init);
+    executor
+        .at("/:container_id/run")
+        .with(drain.clone())
+        .with(admission.clone())
+        .post(core::run);
 
+    executor.at("/health").get(admin::health);
+    executor.at("/ready").get(admin::ready);
+    executor.at("/metrics").get(admin::prometheus);
+    executor.at("/capacity").get(move |_: tide::Request<Router>| {
+        let capacity = admission.capacity();
+        async move { tide::Body::from_json(&capacity) }
+    });
 
-    #[cfg(feature = "wasmtime_component")]
-    let runtime = ow_wasmtime_component::Wasmtime::default();
+    // The admin routes can destroy containers, so they are only served on their own address
+    let mut admin_server = tide::with_state(runtime.clone());
+    admin_server.at("/admin/containers").get(admin::containers);
+    admin_server.at("/admin/containers/:container_id").delete(admin::evict_container);
+    admin_server.at("/admin/caches").get(admin::caches);
+    admin_server.at("/admin/caches/:runtime/:cache").delete(admin::evict_cache_entry);
 
-    #[cfg(feature = "wasmtime_component_nn")]
-    let runtime = ow_wasmtime_component_nn::Wasmtime::default();
+    info!("Listening on: {}", config.address);
 
-    let mut executor = tide::with_state(runtime);
+    let server = async {
+        let listener = executor.listen(config.address.clone());
+        match &config.admin_address {
+            Some(admin_address) => {
+                info!("Serving the admin routes on: {}", admin_address);
+                listener.race(admin_server.listen(admin_address.clone())).await?;
+            }
+            None => listener.await?,
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    // Keeps serving the probes and metrics while the activations in flight finish
+    let stop = async {
+        let signal = shutdown::signal().await?;
+        info!("Received {}, waiting for {} requests in flight", signal, drain.in_flight());
 
-    executor.at("/:container_id/destroy").post(core::destroy);
-    executor.at("/:container_id/init").post(core::init);
-    executor.at("/:container_id/run").post(core::run);
+        runtime.set_ready(false);
+        if !drain.drain(config.shutdown_timeout).await {
+            warn!(
+                "{} requests still in flight after {:?}, exiting anyway",
+                drain.in_flight(),
+                config.shutdown_timeout
+            );
+        }
 
-    println!("Listening on: {}", ADDRESS);
+        Ok::<_, anyhow::Error>(())
+    };
+    server.race(stop).await?;
 
-    executor.listen(ADDRESS).await.unwrap();
+    shutdown::flush(&runtime, &config.engine);
+    info!("Shut down");
 
     Ok(())
 }
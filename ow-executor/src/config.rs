@@ -0,0 +1,349 @@
+use crate::admission::{AdmissionLimits, DEFAULT_MAX_QUEUED_ACTIVATIONS, DEFAULT_QUEUE_TIMEOUT};
+use anyhow::Context;
+use clap::builder::BoolishValueParser;
+use clap::Parser;
+use ow_common::cache::{CacheConfig, EvictionPolicy};
+use ow_common::engine::{self, EngineOptions};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Address the executor listens on when none is configured.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9000";
+/// Log filter used when none is configured.
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+/// How long a shutdown waits for the activations in flight when none is configured.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the executor formats its own logs. Guest logs are always written as is.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    /// One line per event, for people.
+    #[default]
+    Human,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!("Unknown log format {}, expected human or json", s)),
+        }
+    }
+}
+
+/// Command line of the executor. Every setting can also be given as an environment variable or
+/// in the TOML file passed with `--config`.
+#[derive(Debug, Parser)]
+#[command(name = "executor", about = "WebAssembly container runtime for OpenWhisk")]
+struct Cli {
+    /// TOML file with the settings of the executor.
+    #[arg(short, long, env = "OW_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+/// The settings of the executor, as read from one source. Settings that are not set are taken
+/// from the next source, in order: flags, environment variables, configuration file, defaults.
+#[derive(Debug, Default, clap::Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    /// Address to listen on.
+    #[arg(long, env = "OW_ADDRESS")]
+    address: Option<String>,
+    /// Address to serve the admin routes on, apart from the OpenWhisk routes.
+    #[arg(long, env = "OW_ADMIN_ADDRESS")]
+    admin_address: Option<String>,
+    /// Runtime of the actions without a `runtime` annotation, one of the runtime features
+    /// compiled in. Chosen from the kind of artifact if not set.
+    #[arg(long, env = "OW_RUNTIME")]
+    runtime: Option<String>,
+    /// Log level, or a filter such as `info,ow_wasmtime=debug`.
+    #[arg(long, env = "OW_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Format of the logs of the executor, `human` or `json`.
+    #[arg(long, env = "OW_LOG_FORMAT")]
+    log_format: Option<String>,
+    /// OTLP/HTTP endpoint the traces are exported to, e.g. `http://localhost:4318/v1/traces`.
+    #[arg(long, env = "OW_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// Seconds a shutdown waits for the activations in flight before exiting anyway.
+    #[arg(long, env = "OW_SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+
+    /// Activations running at the same time, across all containers. Defaults to the number of
+    /// CPUs.
+    #[arg(long, env = "OW_MAX_ACTIVATIONS")]
+    max_activations: Option<usize>,
+    /// Activations of a single container running at the same time. Defaults to
+    /// `max_activations`.
+    #[arg(long, env = "OW_MAX_CONTAINER_ACTIVATIONS")]
+    max_container_activations: Option<usize>,
+    /// Activations waiting for a slot once the limits are reached, any other is rejected.
+    #[arg(long, env = "OW_MAX_QUEUED_ACTIVATIONS")]
+    max_queued_activations: Option<usize>,
+    /// Milliseconds an activation waits for a slot before it is rejected.
+    #[arg(long, env = "OW_QUEUE_TIMEOUT_MS")]
+    queue_timeout_ms: Option<u64>,
+    /// Models an activation of the wasmtime_nn_parallel runtime runs at the same time. Defaults
+    /// to the number of CPUs.
+    #[arg(long, env = "OW_MAX_MODEL_THREADS")]
+    max_model_threads: Option<usize>,
+
+    /// Run every activation with a fuel budget.
+    #[arg(long, env = "OW_FUEL_METERING", value_parser = BoolishValueParser::new())]
+    fuel_metering: Option<bool>,
+    /// Fuel budget of actions without a `fuel` annotation.
+    #[arg(long, env = "OW_DEFAULT_FUEL")]
+    default_fuel: Option<u64>,
+    /// Memory limit of actions without a `memory_limit_mb` annotation.
+    #[arg(long, env = "OW_DEFAULT_MEMORY_LIMIT_MB")]
+    default_memory_limit_mb: Option<u64>,
+    /// Bytes of output an activation may log.
+    #[arg(long, env = "OW_LOG_LIMIT_BYTES")]
+    log_limit_bytes: Option<usize>,
+    /// Accept actions precompiled into `.cwasm` files.
+    #[arg(long, env = "OW_TRUST_PRECOMPILED", value_parser = BoolishValueParser::new())]
+    trust_precompiled: Option<bool>,
+    /// Directory of the on-disk cache of compiled actions.
+    #[arg(long, env = "OW_MODULE_CACHE_DIR")]
+    module_cache_dir: Option<PathBuf>,
+    /// Directory under which actions may mount host directories with the `dir` annotation.
+    #[arg(long, env = "OW_GUEST_DIR_BASE")]
+    guest_dir_base: Option<PathBuf>,
+
+    #[arg(long, env = "OW_INSTANCE_CACHE_CAPACITY_MB")]
+    instance_cache_capacity_mb: Option<usize>,
+    #[arg(long, env = "OW_INSTANCE_CACHE_MAX_ENTRIES")]
+    instance_cache_max_entries: Option<usize>,
+    #[arg(long, env = "OW_INSTANCE_CACHE_TTL_SECS")]
+    instance_cache_ttl_secs: Option<u64>,
+    #[arg(long, env = "OW_INSTANCE_CACHE_POLICY")]
+    instance_cache_policy: Option<String>,
+
+    #[arg(long, env = "OW_MODEL_CACHE_CAPACITY_MB")]
+    model_cache_capacity_mb: Option<usize>,
+    #[arg(long, env = "OW_MODEL_CACHE_MAX_ENTRIES")]
+    model_cache_max_entries: Option<usize>,
+    #[arg(long, env = "OW_MODEL_CACHE_TTL_SECS")]
+    model_cache_ttl_secs: Option<u64>,
+    #[arg(long, env = "OW_MODEL_CACHE_POLICY")]
+    model_cache_policy: Option<String>,
+
+    /// Timeout of model and image downloads, in seconds.
+    #[arg(long, env = "OW_DOWNLOAD_TIMEOUT_SECS")]
+    download_timeout_secs: Option<u64>,
+    /// Region of the S3 client when the AWS configuration does not set one.
+    #[arg(long, env = "OW_S3_REGION")]
+    s3_region: Option<String>,
+    /// Graphs a container keeps loaded from the `model` parameters of its activations.
+    #[arg(long, env = "OW_MAX_CONTAINER_GRAPHS")]
+    max_container_graphs: Option<usize>,
+}
+
+impl Settings {
+    /// Returns the settings of `self`, falling back to `other` for those not set.
+    fn or(self, other: Settings) -> Settings {
+        Settings {
+            address: self.address.or(other.address),
+            admin_address: self.admin_address.or(other.admin_address),
+            runtime: self.runtime.or(other.runtime),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            otlp_endpoint: self.otlp_endpoint.or(other.otlp_endpoint),
+            shutdown_timeout_secs: self.shutdown_timeout_secs.or(other.shutdown_timeout_secs),
+            max_activations: self.max_activations.or(other.max_activations),
+            max_container_activations: self.max_container_activations.or(other.max_container_activations),
+            max_queued_activations: self.max_queued_activations.or(other.max_queued_activations),
+            queue_timeout_ms: self.queue_timeout_ms.or(other.queue_timeout_ms),
+            max_model_threads: self.max_model_threads.or(other.max_model_threads),
+            fuel_metering: self.fuel_metering.or(other.fuel_metering),
+            default_fuel: self.default_fuel.or(other.default_fuel),
+            default_memory_limit_mb: self.default_memory_limit_mb.or(other.default_memory_limit_mb),
+            log_limit_bytes: self.log_limit_bytes.or(other.log_limit_bytes),
+            trust_precompiled: self.trust_precompiled.or(other.trust_precompiled),
+            module_cache_dir: self.module_cache_dir.or(other.module_cache_dir),
+            guest_dir_base: self.guest_dir_base.or(other.guest_dir_base),
+            instance_cache_capacity_mb: self.instance_cache_capacity_mb.or(other.instance_cache_capacity_mb),
+            instance_cache_max_entries: self.instance_cache_max_entries.or(other.instance_cache_max_entries),
+            instance_cache_ttl_secs: self.instance_cache_ttl_secs.or(other.instance_cache_ttl_secs),
+            instance_cache_policy: self.instance_cache_policy.or(other.instance_cache_policy),
+            model_cache_capacity_mb: self.model_cache_capacity_mb.or(other.model_cache_capacity_mb),
+            model_cache_max_entries: self.model_cache_max_entries.or(other.model_cache_max_entries),
+            model_cache_ttl_secs: self.model_cache_ttl_secs.or(other.model_cache_ttl_secs),
+            model_cache_policy: self.model_cache_policy.or(other.model_cache_policy),
+            download_timeout_secs: self.download_timeout_secs.or(other.download_timeout_secs),
+            s3_region: self.s3_region.or(other.s3_region),
+            max_container_graphs: self.max_container_graphs.or(other.max_container_graphs),
+        }
+    }
+}
+
+/// Configuration of the executor, resolved from all its sources.
+#[derive(Debug, Clone)]
+pub struct ExecutorConfig {
+    pub address: String,
+    /// Where the admin routes are served, not served if `None`.
+    pub admin_address: Option<String>,
+    /// Runtime of the actions without a `runtime` annotation, or `None` to choose it from the
+    /// kind of artifact.
+    pub runtime: Option<String>,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    /// Where traces are exported to, not exported if `None`.
+    pub otlp_endpoint: Option<String>,
+    /// How long a shutdown waits for the activations in flight.
+    pub shutdown_timeout: Duration,
+    /// Limits on the activations running at the same time.
+    pub admission: AdmissionLimits,
+    /// Options every runtime is constructed with.
+    pub engine: EngineOptions,
+}
+
+impl ExecutorConfig {
+    /// Reads the configuration from the command line, the environment and the configuration
+    /// file, if one is given.
+    pub fn load() -> anyhow::Result<Self> {
+        let cli = Cli::parse();
+
+        let file = match &cli.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?
+            }
+            None => Settings::default(),
+        };
+
+        Self::from_settings(cli.settings.or(file))
+    }
+
+    fn from_settings(settings: Settings) -> anyhow::Result<Self> {
+        let defaults = EngineOptions::default();
+
+        let instance_cache = cache_config(
+            defaults.instance_cache,
+            settings.instance_cache_capacity_mb,
+            settings.instance_cache_max_entries,
+            settings.instance_cache_ttl_secs,
+            settings.instance_cache_policy,
+        )?;
+        let model_cache = cache_config(
+            defaults.model_cache,
+            settings.model_cache_capacity_mb,
+            settings.model_cache_max_entries,
+            settings.model_cache_ttl_secs,
+            settings.model_cache_policy,
+        )?;
+
+        let engine = EngineOptions {
+            fuel_metering: settings.fuel_metering.unwrap_or(defaults.fuel_metering),
+            default_fuel: settings.default_fuel,
+            default_memory_limit_mb: settings.default_memory_limit_mb,
+            log_limit_bytes: settings.log_limit_bytes,
+            trust_precompiled: settings.trust_precompiled.unwrap_or(defaults.trust_precompiled),
+            module_cache_dir: settings.module_cache_dir,
+            guest_dir_base: settings.guest_dir_base,
+            instance_cache,
+            model_cache,
+            download_timeout: settings
+                .download_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.download_timeout),
+            s3_region: settings.s3_region.unwrap_or(defaults.s3_region),
+            max_model_threads: settings.max_model_threads.unwrap_or(defaults.max_model_threads),
+            max_container_graphs: settings
+                .max_container_graphs
+                .unwrap_or(defaults.max_container_graphs),
+        };
+
+        let max_activations = settings.max_activations.unwrap_or_else(engine::default_parallelism);
+        let admission = AdmissionLimits {
+            max_activations,
+            max_container_activations: settings.max_container_activations.unwrap_or(max_activations),
+            max_queued: settings.max_queued_activations.unwrap_or(DEFAULT_MAX_QUEUED_ACTIVATIONS),
+            queue_timeout: settings
+                .queue_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_QUEUE_TIMEOUT),
+        };
+
+        Ok(Self {
+            address: settings.address.unwrap_or_else(|| DEFAULT_ADDRESS.to_owned()),
+            admin_address: settings.admin_address,
+            runtime: settings.runtime,
+            log_level: settings.log_level.unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_owned()),
+            log_format: match settings.log_format {
+                Some(format) => format.parse()?,
+                None => LogFormat::default(),
+            },
+            otlp_endpoint: settings.otlp_endpoint,
+            shutdown_timeout: settings
+                .shutdown_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            admission,
+            engine,
+        })
+    }
+}
+
+fn cache_config(
+    defaults: CacheConfig,
+    capacity_mb: Option<usize>,
+    max_entries: Option<usize>,
+    ttl_secs: Option<u64>,
+    policy: Option<String>,
+) -> anyhow::Result<CacheConfig> {
+    let policy = match policy {
+        Some(policy) => policy.parse::<EvictionPolicy>()?,
+        None => defaults.policy,
+    };
+
+    Ok(CacheConfig {
+        capacity_bytes: capacity_mb.map(|mb| mb * 1024 * 1024).or(defaults.capacity_bytes),
+        max_entries: max_entries.or(defaults.max_entries),
+        ttl: ttl_secs.map(Duration::from_secs).or(defaults.ttl),
+        policy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_configuration_file() {
+        let file: Settings = toml::from_str(
+            r#"
+            address = "0.0.0.0:8080"
+            runtime = "wasmtime_component"
+            instance_cache_policy = "lfu"
+            download_timeout_secs = 30
+            "#,
+        )
+        .unwrap();
+        let cli = Cli::parse_from(["executor", "--address", "0.0.0.0:9090"]);
+
+        let config = ExecutorConfig::from_settings(cli.settings.or(file)).unwrap();
+
+        assert_eq!(config.address, "0.0.0.0:9090");
+        assert_eq!(config.admin_address, None);
+        assert_eq!(config.runtime.as_deref(), Some("wasmtime_component"));
+        assert_eq!(config.engine.instance_cache.policy, EvictionPolicy::Lfu);
+        assert_eq!(config.engine.download_timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(toml::from_str::<Settings>("adress = \"0.0.0.0:8080\"").is_err());
+    }
+}
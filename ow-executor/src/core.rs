@@ -1,7 +1,14 @@
+use crate::telemetry::{self, HeaderExtractor};
 use async_std::task;
-use ow_common::{util, ActivationContext, ActivationInit, ActivationResponse, WasmRuntime};
+use ow_common::metrics::metrics;
+use ow_common::{
+    catch_panic, util, ActivationContext, ActivationInit, ActivationResponse,
+    ActivationResponseStatus, Classify, WasmRuntime,
+};
 use serde::Serialize;
-use tide::{Request, StatusCode};
+use std::time::Instant;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{debug, info, info_span, warn};
 
 #[allow(dead_code)]
 #[derive(Serialize)]
@@ -14,62 +21,127 @@ struct RuntimeResponse {
 pub async fn destroy(mut req: Request<impl WasmRuntime>) -> tide::Result<StatusCode> {
     let container_id = req.body_string().await?;
 
-    println!("ow-executor Removing wasm container (instance) with id {}", container_id);
+    let span = info_span!("destroy", container_id = %container_id);
+    let _enter = span.enter();
+
+    info!("Removing wasm container");
 
     req.state().destroy(&container_id);
+    metrics().container_destroyed(&container_id);
 
     Ok(StatusCode::Ok)
 }
 
-pub async fn init(mut req: Request<impl WasmRuntime>) -> tide::Result<StatusCode> {
+pub async fn init(
+    mut req: Request<impl WasmRuntime + Send + Sync + 'static>,
+) -> tide::Result<StatusCode> {
     let activation_init = req.body_json().await;
 
     if let Err(err) = &activation_init {
-        println!("\n\n/init err: {:?}", err);
+        warn!("Invalid /init request: {:?}", err);
     }
 
     let activation_init: ActivationInit = activation_init?;
-
-    println!("\n\now-executor /init {}", activation_init.value.name);
-
     let container_id = req.param("container_id").unwrap().to_owned();
 
-    println!("Initializing wasm container(instance) with id {}", container_id);
-
-    let runtime = req.state();
+    let span = info_span!(
+        "init",
+        container_id = %container_id,
+        action_name = %activation_init.value.name,
+    );
+    telemetry::set_parent(&span, &HeaderExtractor(&req));
+    span.in_scope(|| info!("Initializing wasm container"));
 
-    let module_bytes = util::b64_decode(activation_init.value.code)?;
+    let runtime = req.state().clone();
+    let start = Instant::now();
 
-    let module = util::unzip(module_bytes)?;
+    // Compiling the action and loading its models can take seconds, so it runs on the blocking
+    // pool like activations do, instead of stalling the executor threads that serve requests
+    let init_span = span.clone();
+    let result = task::spawn_blocking(move || {
+        init_span.in_scope(|| {
+            catch_panic(|| {
+                util::b64_decode(activation_init.value.code)
+                    .and_then(util::unzip)
+                    .developer_error()
+                    .and_then(|module| {
+                        runtime.initialize(
+                            container_id,
+                            activation_init.value.annotations,
+                            activation_init.value.env,
+                            module,
+                        )
+                    })
+            })
+        })
+    })
+    .await;
 
-    runtime.initialize(container_id, activation_init.value.annotations, module)?;
+    let _enter = span.enter();
+    metrics().init_finished(result.is_ok(), start.elapsed());
+    if let Err(err) = result {
+        warn!("Failed to initialize: {:#}", err);
+        return Err(tide::Error::new(status_code(err.status()), err));
+    }
 
     Ok(StatusCode::Ok)
 }
 
 pub async fn run(
     mut req: Request<impl WasmRuntime + Send + Sync + 'static>,
-) -> tide::Result<serde_json::Value> {
+) -> tide::Result<Response> {
     let activation_context: ActivationContext = req.body_json().await?;
 
-    println!("\now-executor /run {}", activation_context.action_name);
+    let container_id = req.param("container_id").unwrap().to_owned();
 
-    println!(
-        "Running wasm container (instance) with id {}",
-        req.param("container_id").unwrap()
+    let span = info_span!(
+        "run",
+        container_id = %container_id,
+        activation_id = %activation_context.activation_id,
+        transaction_id = %activation_context.transaction_id,
+        action_name = %activation_context.action_name,
     );
+    telemetry::set_parent(&span, &HeaderExtractor(&req));
+    span.in_scope(|| info!("Running wasm container"));
 
     // Create a cheap clone of the runtime that can be moved onto another thread
     let runtime = req.state().clone();
 
+    let start = Instant::now();
+    metrics().activation_started();
+
+    let id = container_id.clone();
+    let guest_span = span.clone();
+    // A panic fails this activation only, instead of the thread of the blocking pool it ran on
     let result = task::spawn_blocking(move || {
-        runtime.run(req.param("container_id").unwrap(), activation_context.value)
+        guest_span.in_scope(|| catch_panic(|| runtime.run(&id, activation_context)))
     })
     .await;
 
-    println!("Wasm Execution returned {:?}", result);
+    let _enter = span.enter();
+    debug!("Wasm execution returned {:?}", result);
+
+    let (status, response) = match result {
+        Ok(outcome) => (StatusCode::Ok, ActivationResponse::new(outcome)),
+        Err(failure) => (
+            status_code(failure.error.status()),
+            ActivationResponse::error(failure),
+        ),
+    };
 
-    let response = ActivationResponse::new(result?);
+    metrics().activation_finished(&container_id, response.status(), start.elapsed());
 
-    Ok(serde_json::to_value(response).unwrap())
+    let mut res = Response::new(status);
+    res.set_body(Body::from_json(&response)?);
+
+    Ok(res)
+}
+
+/// Failures of the action and of the executor are told apart by the status code, so that the
+/// invoker only retries the latter.
+fn status_code(status: ActivationResponseStatus) -> StatusCode {
+    match status {
+        ActivationResponseStatus::WhiskInternalError => StatusCode::InternalServerError,
+        _ => StatusCode::BadGateway,
+    }
 }
@@ -1,2 +1,10 @@
+pub mod admission;
+pub mod admin;
+pub mod config;
 pub mod core;
+pub mod logging;
+pub mod router;
+#[allow(clippy::module_inception, clippy::let_and_return)]
 mod runtime_tests;
+pub mod shutdown;
+pub mod telemetry;
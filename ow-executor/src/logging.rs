@@ -0,0 +1,36 @@
+use crate::config::{ExecutorConfig, LogFormat};
+use crate::telemetry::Telemetry;
+use anyhow::anyhow;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Installs the global subscriber that writes the logs of the executor and its runtimes, and
+/// exports their spans if `otlp_endpoint` is set. Traces are exported while the returned
+/// `Telemetry` is alive.
+pub fn init(config: &ExecutorConfig) -> anyhow::Result<Option<Telemetry>> {
+    let filter = EnvFilter::try_new(&config.log_level)?;
+
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match config.log_format {
+        LogFormat::Human => fmt.boxed(),
+        // Events carry the fields of their spans, so that every line of an activation can be
+        // found by its activation id
+        LogFormat::Json => fmt.json().with_current_span(true).with_span_list(true).boxed(),
+    };
+
+    let telemetry = config
+        .otlp_endpoint
+        .as_deref()
+        .map(Telemetry::new)
+        .transpose()?;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(telemetry.as_ref().map(Telemetry::layer))
+        .try_init()
+        .map_err(|err| anyhow!("Failed to install the log subscriber: {}", err))?;
+
+    Ok(telemetry)
+}
@@ -0,0 +1,407 @@
+use crate::config::ExecutorConfig;
+use anyhow::anyhow;
+use dashmap::DashMap;
+use ow_common::artifact::{self, ArtifactKind};
+use ow_common::cache::CacheInfo;
+use ow_common::{
+    ActionCapabilities, ActivationContext, ActivationFailure, ActivationOutcome, Classify,
+    ContainerInfo, RuntimeError, WasmRuntime,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The runtimes an executor can host, named after the cargo feature that compiles them in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RuntimeKind {
+    Wasmtime,
+    WasmtimeNn,
+    WasmtimeNnParallel,
+    WasmtimeComponent,
+    WasmtimeComponentNn,
+}
+
+impl RuntimeKind {
+    pub const ALL: [RuntimeKind; 5] = [
+        RuntimeKind::Wasmtime,
+        RuntimeKind::WasmtimeNn,
+        RuntimeKind::WasmtimeNnParallel,
+        RuntimeKind::WasmtimeComponent,
+        RuntimeKind::WasmtimeComponentNn,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RuntimeKind::Wasmtime => "wasmtime",
+            RuntimeKind::WasmtimeNn => "wasmtime_nn",
+            RuntimeKind::WasmtimeNnParallel => "wasmtime_nn_parallel",
+            RuntimeKind::WasmtimeComponent => "wasmtime_component",
+            RuntimeKind::WasmtimeComponentNn => "wasmtime_component_nn",
+        }
+    }
+
+    /// Whether the feature of this runtime was enabled when building the executor.
+    pub fn is_available(&self) -> bool {
+        match self {
+            RuntimeKind::Wasmtime => cfg!(feature = "wasmtime"),
+            RuntimeKind::WasmtimeNn => cfg!(feature = "wasmtime_nn"),
+            RuntimeKind::WasmtimeNnParallel => cfg!(feature = "wasmtime_nn_parallel"),
+            RuntimeKind::WasmtimeComponent => cfg!(feature = "wasmtime_component"),
+            RuntimeKind::WasmtimeComponentNn => cfg!(feature = "wasmtime_component_nn"),
+        }
+    }
+
+    pub fn available() -> Vec<RuntimeKind> {
+        Self::ALL.into_iter().filter(RuntimeKind::is_available).collect()
+    }
+
+    fn is_component(&self) -> bool {
+        matches!(self, RuntimeKind::WasmtimeComponent | RuntimeKind::WasmtimeComponentNn)
+    }
+
+    fn is_nn(&self) -> bool {
+        matches!(
+            self,
+            RuntimeKind::WasmtimeNn | RuntimeKind::WasmtimeNnParallel | RuntimeKind::WasmtimeComponentNn
+        )
+    }
+}
+
+impl std::fmt::Display for RuntimeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for RuntimeKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| anyhow!("Unknown runtime {}", s))
+    }
+}
+
+/// Calls `$body` with `$runtime` bound to the runtime of `$kind`, which must be available.
+macro_rules! dispatch {
+    ($router:expr, $kind:expr, $runtime:ident => $body:expr) => {
+        match $kind {
+            #[cfg(feature = "wasmtime")]
+            RuntimeKind::Wasmtime => {
+                let $runtime = &$router.wasmtime;
+                $body
+            }
+            #[cfg(feature = "wasmtime_nn")]
+            RuntimeKind::WasmtimeNn => {
+                let $runtime = &$router.wasmtime_nn;
+                $body
+            }
+            #[cfg(feature = "wasmtime_nn_parallel")]
+            RuntimeKind::WasmtimeNnParallel => {
+                let $runtime = &$router.wasmtime_nn_parallel;
+                $body
+            }
+            #[cfg(feature = "wasmtime_component")]
+            RuntimeKind::WasmtimeComponent => {
+                let $runtime = &$router.wasmtime_component;
+                $body
+            }
+            #[cfg(feature = "wasmtime_component_nn")]
+            RuntimeKind::WasmtimeComponentNn => {
+                let $runtime = &$router.wasmtime_component_nn;
+                $body
+            }
+            #[allow(unreachable_patterns)]
+            kind => unreachable!("The {} runtime is not compiled in", kind),
+        }
+    };
+}
+
+/// Hosts every runtime compiled into the executor, and routes each container to one of them.
+///
+/// The runtime of a container is chosen on init from its `runtime` annotation, or else from the
+/// runtime set in the configuration, or else from the kind of artifact: components go to the
+/// first component runtime available and modules to the first module runtime, or to the first
+/// wasi-nn one if they import wasi-nn. Runs and destroys then go to the runtime that initialized
+/// the container.
+#[derive(Clone)]
+pub struct Router {
+    #[cfg(feature = "wasmtime")]
+    wasmtime: ow_wasmtime::Wasmtime,
+    #[cfg(feature = "wasmtime_nn")]
+    wasmtime_nn: ow_wasmtime_nn::Wasmtime,
+    #[cfg(feature = "wasmtime_nn_parallel")]
+    wasmtime_nn_parallel: ow_wasmtime_nn_parallel::Wasmtime,
+    #[cfg(feature = "wasmtime_component")]
+    wasmtime_component: ow_wasmtime_component::Wasmtime,
+    #[cfg(feature = "wasmtime_component_nn")]
+    wasmtime_component_nn: ow_wasmtime_component_nn::Wasmtime,
+    default_runtime: Option<RuntimeKind>,
+    containers: Arc<DashMap<String, RuntimeKind>>,
+    ready: Arc<AtomicBool>,
+}
+
+impl Router {
+    pub fn new(config: &ExecutorConfig) -> anyhow::Result<Self> {
+        let default_runtime = match &config.runtime {
+            Some(name) => Some(available(name.parse()?)?),
+            None => None,
+        };
+
+        Ok(Self {
+            #[cfg(feature = "wasmtime")]
+            wasmtime: ow_wasmtime::Wasmtime::new(config.engine.clone()),
+            #[cfg(feature = "wasmtime_nn")]
+            wasmtime_nn: ow_wasmtime_nn::Wasmtime::new(config.engine.clone()),
+            #[cfg(feature = "wasmtime_nn_parallel")]
+            wasmtime_nn_parallel: ow_wasmtime_nn_parallel::Wasmtime::new(config.engine.clone()),
+            #[cfg(feature = "wasmtime_component")]
+            wasmtime_component: ow_wasmtime_component::Wasmtime::new(config.engine.clone()),
+            #[cfg(feature = "wasmtime_component_nn")]
+            wasmtime_component_nn: ow_wasmtime_component_nn::Wasmtime::new(config.engine.clone()),
+            default_runtime,
+            containers: Arc::new(DashMap::new()),
+            ready: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    /// Whether the executor accepts new containers.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// The runtime that hosts `container_id`, if it was initialized.
+    pub fn runtime_of(&self, container_id: &str) -> Option<RuntimeKind> {
+        self.containers.get(container_id).map(|kind| *kind)
+    }
+
+    /// An unknown `runtime` annotation or an artifact that is not wasm is the fault of the
+    /// action, while a runtime that is not compiled in is the fault of the executor.
+    fn select(
+        &self,
+        capabilities: &ActionCapabilities,
+        module: &[u8],
+    ) -> Result<RuntimeKind, RuntimeError> {
+        if let Some(name) = &capabilities.runtime {
+            return available(name.parse().developer_error()?);
+        }
+        if let Some(kind) = self.default_runtime {
+            return Ok(kind);
+        }
+
+        let component = match artifact::detect(module) {
+            Some(ArtifactKind::Component | ArtifactKind::PrecompiledComponent) => true,
+            Some(ArtifactKind::Module | ArtifactKind::PrecompiledModule) => false,
+            None => {
+                return Err(RuntimeError::developer(
+                    "The action is neither a wasm module nor a component",
+                ))
+            }
+        };
+
+        // Any runtime of the right kind runs actions without wasi-nn, but only the wasi-nn ones
+        // link the actions that import it
+        let nn = artifact::imports_wasi_nn(module);
+
+        RuntimeKind::available()
+            .into_iter()
+            .find(|kind| kind.is_component() == component && (kind.is_nn() || !nn))
+            .ok_or_else(|| {
+                RuntimeError::internal(format!(
+                    "No {}{} runtime is compiled in this executor",
+                    if nn { "wasi-nn " } else { "" },
+                    if component { "component" } else { "module" }
+                ))
+            })
+    }
+}
+
+fn available(kind: RuntimeKind) -> Result<RuntimeKind, RuntimeError> {
+    if kind.is_available() {
+        Ok(kind)
+    } else {
+        Err(RuntimeError::internal(format!(
+            "The {} runtime is not available, build the executor with the {} feature",
+            kind, kind
+        )))
+    }
+}
+
+impl WasmRuntime for Router {
+    fn initialize(
+        &self,
+        container_id: String,
+        capabilities: ActionCapabilities,
+        env: HashMap<String, String>,
+        module: Vec<u8>,
+    ) -> Result<(), RuntimeError> {
+        let kind = self.select(&capabilities, &module)?;
+
+        info!("Initializing container {} with the {} runtime", container_id, kind);
+
+        dispatch!(self, kind, runtime => {
+            runtime.initialize(container_id.clone(), capabilities, env, module)
+        })?;
+
+        // A container initialized again with another runtime is dropped by the previous one
+        match self.containers.insert(container_id.clone(), kind) {
+            Some(previous) if previous != kind => {
+                dispatch!(self, previous, runtime => runtime.destroy(&container_id))
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        container_id: &str,
+        context: ActivationContext,
+    ) -> Result<ActivationOutcome, ActivationFailure> {
+        let kind = self
+            .runtime_of(container_id)
+            .ok_or_else(|| RuntimeError::internal(format!("No action named {}", container_id)))?;
+
+        dispatch!(self, kind, runtime => runtime.run(container_id, context))
+    }
+
+    fn destroy(&self, container_id: &str) {
+        match self.containers.remove(container_id) {
+            Some((_, kind)) => dispatch!(self, kind, runtime => runtime.destroy(container_id)),
+            None => warn!("No container with id {} existed.", container_id),
+        }
+    }
+
+    fn containers(&self) -> Vec<ContainerInfo> {
+        RuntimeKind::available()
+            .into_iter()
+            .flat_map(|kind| dispatch!(self, kind, runtime => runtime.containers()))
+            .collect()
+    }
+
+    /// The caches of every runtime, named `<runtime>/<cache>`.
+    fn caches(&self) -> Vec<CacheInfo> {
+        RuntimeKind::available()
+            .into_iter()
+            .flat_map(|kind| {
+                let caches = dispatch!(self, kind, runtime => runtime.caches());
+                caches.into_iter().map(move |mut cache| {
+                    cache.name = format!("{}/{}", kind, cache.name);
+                    cache
+                })
+            })
+            .collect()
+    }
+
+    fn evict(&self, cache: &str, key: &str) -> usize {
+        let Some((kind, cache)) = cache.split_once('/') else {
+            return 0;
+        };
+        match kind.parse::<RuntimeKind>() {
+            Ok(kind) if kind.is_available() => {
+                dispatch!(self, kind, runtime => runtime.evict(cache, key))
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ow_common::ActivationResponseStatus;
+
+    #[test]
+    fn runtime_names_round_trip() {
+        for kind in RuntimeKind::ALL {
+            assert_eq!(kind.name().parse::<RuntimeKind>().unwrap(), kind);
+        }
+        assert!("wasmtime_rt".parse::<RuntimeKind>().is_err());
+    }
+
+    #[cfg(feature = "wasmtime")]
+    fn router() -> Router {
+        Router {
+            wasmtime: ow_wasmtime::Wasmtime::default(),
+            #[cfg(feature = "wasmtime_nn")]
+            wasmtime_nn: ow_wasmtime_nn::Wasmtime::default(),
+            #[cfg(feature = "wasmtime_nn_parallel")]
+            wasmtime_nn_parallel: ow_wasmtime_nn_parallel::Wasmtime::default(),
+            #[cfg(feature = "wasmtime_component")]
+            wasmtime_component: ow_wasmtime_component::Wasmtime::default(),
+            #[cfg(feature = "wasmtime_component_nn")]
+            wasmtime_component_nn: ow_wasmtime_component_nn::Wasmtime::default(),
+            default_runtime: None,
+            containers: Arc::new(DashMap::new()),
+            ready: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn modules_importing_wasi_nn_go_to_a_wasi_nn_runtime() {
+        let router = router();
+        let capabilities = ActionCapabilities::default();
+        let module = b"\0asm\x01\0\0\0";
+        // Imports `load` from `wasi_ephemeral_nn`
+        let nn_module = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x02\x1a\x01\x11wasi_ephemeral_nn\x04load\0\0";
+
+        assert_eq!(router.select(&capabilities, module).unwrap(), RuntimeKind::Wasmtime);
+        match RuntimeKind::available().into_iter().find(|kind| kind.is_nn() && !kind.is_component()) {
+            Some(kind) => assert_eq!(router.select(&capabilities, nn_module).unwrap(), kind),
+            None => assert_eq!(
+                router.select(&capabilities, nn_module).unwrap_err().status(),
+                ActivationResponseStatus::WhiskInternalError
+            ),
+        }
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn test_unknown_containers_are_internal_errors() {
+        let router = router();
+        let not_wasm = b"#!/bin/sh".to_vec();
+        let context = ActivationContext {
+            value: serde_json::json!({}),
+            namespace: "guest".to_owned(),
+            action_name: "a".to_owned(),
+            api_host: None,
+            api_key: None,
+            activation_id: "activation_id".to_owned(),
+            transaction_id: "transaction_id".to_owned(),
+            deadline: 0,
+        };
+
+        let err = router
+            .initialize("a".to_owned(), Default::default(), HashMap::new(), not_wasm)
+            .unwrap_err();
+        assert_eq!(err.status(), ActivationResponseStatus::ActionDeveloperError);
+
+        let failure = router.run("a", context).unwrap_err();
+        assert_eq!(failure.error.status(), ActivationResponseStatus::WhiskInternalError);
+    }
+
+    #[cfg(all(feature = "wasmtime", feature = "wasmtime_component"))]
+    #[test]
+    fn initializing_again_with_another_runtime_destroys_the_previous_container() {
+        let router = router();
+        let module = b"\0asm\x01\0\0\0".to_vec();
+        let component = b"\0asm\x0d\0\x01\0".to_vec();
+
+        router.initialize("a".to_owned(), Default::default(), HashMap::new(), module).unwrap();
+        assert_eq!(router.runtime_of("a"), Some(RuntimeKind::Wasmtime));
+
+        router.initialize("a".to_owned(), Default::default(), HashMap::new(), component).unwrap();
+        assert_eq!(router.runtime_of("a"), Some(RuntimeKind::WasmtimeComponent));
+        assert!(router.wasmtime.containers().is_empty());
+        assert_eq!(router.wasmtime_component.containers().len(), 1);
+    }
+}
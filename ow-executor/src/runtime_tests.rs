@@ -1,9 +1,11 @@
 #[cfg(test)]
 mod runtime_tests {
 
+    use std::collections::HashMap;
     use std::fs::read;
 
-    use ow_common::{ActionCapabilities, WasmRuntime};
+    use ow_common::engine::EngineOptions;
+    use ow_common::{ActionCapabilities, ActivationContext, WasmRuntime};
 
     #[cfg(test)]
     pub fn execute_precompiled_wasm(
@@ -11,23 +13,39 @@ mod runtime_tests {
         capabilities: ActionCapabilities,
         input: serde_json::Value,
     ) -> Result<serde_json::Value, serde_json::Value> {
-        #[cfg(feature = "wasmtime_rt")]
-        let runtime = ow_wasmtime::Wasmtime::default();
+        #[cfg(feature = "wasmtime")]
+        let runtime = ow_wasmtime::Wasmtime::new(EngineOptions {
+            guest_dir_base: Some("/tmp".into()),
+            ..Default::default()
+        });
 
 
         runtime
-            .initialize("action_name".to_owned(), capabilities, module_bytes)
+            .initialize("action_name".to_owned(), capabilities, HashMap::new(), module_bytes)
             .unwrap();
 
-        let result = runtime.run("action_name", input).unwrap();
+        let outcome = runtime.run("action_name", activation_context(input)).unwrap();
+
+        outcome.result
+    }
 
-        result
+    fn activation_context(input: serde_json::Value) -> ActivationContext {
+        ActivationContext {
+            value: input,
+            namespace: "guest".to_owned(),
+            action_name: "action_name".to_owned(),
+            api_host: None,
+            api_key: None,
+            activation_id: "activation_id".to_owned(),
+            transaction_id: "transaction_id".to_owned(),
+            deadline: 0,
+        }
     }
 
     fn get_module_bytes(path: &str) -> Vec<u8> {
         let mut path = path.to_owned();
 
-        #[cfg(feature = "wasmtime_rt")]
+        #[cfg(feature = "wasmtime")]
         path.push_str(".wasmtime");
 
         let path: std::path::PathBuf = path.into();
@@ -103,6 +121,7 @@ mod runtime_tests {
 
         let capabilities = ActionCapabilities {
             dir: Some("/tmp/filesys".into()),
+            dir_readonly: Some(false),
             ..Default::default()
         };
 
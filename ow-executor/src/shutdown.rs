@@ -0,0 +1,127 @@
+use crate::router::Router;
+use async_std::stream::StreamExt;
+use async_std::task;
+use ow_common::engine::EngineOptions;
+use ow_common::metrics::metrics;
+use ow_common::module_cache::ModuleCache;
+use ow_common::WasmRuntime;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::{Next, Request, Response, StatusCode};
+use tracing::{error, info};
+
+/// How often a drain checks whether the requests in flight have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tracks the requests in flight on the routes it is attached to, so that a shutdown can stop
+/// accepting new ones and wait for the others to finish.
+#[derive(Clone, Default)]
+pub struct Drain {
+    state: Arc<DrainState>,
+}
+
+#[derive(Default)]
+struct DrainState {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// A request in flight, until it is dropped.
+struct InFlight(Arc<DrainState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drain {
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::SeqCst)
+    }
+
+    /// Registers a new request, or returns `None` if the drain has started.
+    fn enter(&self) -> Option<InFlight> {
+        // Counted before checking, so that a drain that started in between still waits for it
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(self.state.clone());
+
+        if self.is_draining() {
+            None
+        } else {
+            Some(in_flight)
+        }
+    }
+
+    /// Rejects new requests from now on and waits, up to `timeout`, for those in flight.
+    /// Returns whether they all finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.state.draining.store(true, Ordering::SeqCst);
+
+        let start = Instant::now();
+        while self.in_flight() > 0 {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            task::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        true
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Drain {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match self.enter() {
+            Some(_in_flight) => Ok(next.run(req).await),
+            None => Ok(Response::new(StatusCode::ServiceUnavailable)),
+        }
+    }
+}
+
+/// Waits for a SIGTERM or a SIGINT, and returns its name.
+pub async fn signal() -> anyhow::Result<&'static str> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let signal = signals.next().await.unwrap_or(SIGTERM);
+
+    Ok(signal_hook::low_level::signal_name(signal).unwrap_or("signal"))
+}
+
+/// Persists what the executor would otherwise lose on exit: the on-disk cache of compiled
+/// actions, and the metrics since the last scrape, which are written to the log.
+pub fn flush(router: &Router, options: &EngineOptions) {
+    if let Err(err) = ModuleCache::flush(options) {
+        error!("Failed to flush the disk cache: {:#}", err);
+    }
+
+    match metrics().encode(&router.caches()) {
+        Ok(text) => info!("Final metrics:\n{}", text),
+        Err(err) => error!("Failed to encode the final metrics: {:#}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn drain_waits_for_requests_in_flight() {
+        let drain = Drain::default();
+        let in_flight = drain.enter().unwrap();
+
+        assert!(!drain.drain(Duration::from_millis(100)).await);
+        assert!(drain.enter().is_none());
+        assert_eq!(drain.in_flight(), 1);
+
+        drop(in_flight);
+        assert!(drain.drain(Duration::from_millis(100)).await);
+    }
+}
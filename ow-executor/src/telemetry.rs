@@ -0,0 +1,141 @@
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Name under which the executor reports its traces.
+pub const SERVICE_NAME: &str = "ow-executor";
+
+/// Exports the spans of the executor as OTLP traces while it is alive.
+pub struct Telemetry {
+    provider: TracerProvider,
+    // The exporter sends its batches from this runtime, since the executor runs on async-std
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl Telemetry {
+    /// Starts exporting spans over OTLP/HTTP to `endpoint`, e.g. `http://localhost:4318/v1/traces`.
+    pub fn new(endpoint: &str) -> anyhow::Result<Self> {
+        let tokio = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("otlp-export")
+            .enable_all()
+            .build()?;
+        let provider = {
+            // The batch processor spawns its task on the runtime it is built in
+            let _guard = tokio.enter();
+
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()?;
+            TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+                .build()
+        };
+
+        Ok(Self {
+            provider,
+            _runtime: tokio,
+        })
+    }
+
+    /// The layer that turns `tracing` spans into OTLP spans.
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer(SERVICE_NAME))
+    }
+
+    /// Exports the spans that are still buffered.
+    pub fn flush(&self) {
+        for result in self.provider.force_flush() {
+            if let Err(err) = result {
+                tracing::warn!("Failed to export traces: {}", err);
+            }
+        }
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        self.flush();
+        let _ = self.provider.shutdown();
+    }
+}
+
+/// Makes `span` a child of the W3C `traceparent` found in `headers`, if any, so that the
+/// executor spans show up inside the trace of the caller.
+pub fn set_parent(span: &Span, headers: &impl Extractor) {
+    if headers.get("traceparent").is_none() {
+        return;
+    }
+
+    let context = TraceContextPropagator::new().extract(headers);
+    span.set_parent(context);
+}
+
+/// Reads the trace context from the headers of a tide request.
+pub struct HeaderExtractor<'a, S>(pub &'a tide::Request<S>);
+
+impl<S> Extractor for HeaderExtractor<'_, S> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.header(key).map(|values| values.last().as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.header_names().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceContextExt;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use std::collections::HashMap;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans_continue_the_trace_of_the_caller() {
+        // Stands in for the collector
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let headers = HashMap::from([(
+            "traceparent".to_owned(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_owned(),
+        )]);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("run");
+            set_parent(&span, &headers);
+            span.in_scope(|| tracing::info_span!("b64_decode").in_scope(|| ()));
+            assert!(span.context().span().span_context().is_valid());
+        });
+        let _ = provider.force_flush();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let run = spans.iter().find(|span| span.name == "run").unwrap();
+        let decode = spans.iter().find(|span| span.name == "b64_decode").unwrap();
+
+        assert_eq!(
+            run.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(run.parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(decode.parent_span_id, run.span_context.span_id());
+    }
+}
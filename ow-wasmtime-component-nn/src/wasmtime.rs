@@ -1,19 +1,31 @@
-use std::{sync::Arc, time::Duration,};
+use std::sync::Arc;
+use std::collections::HashMap;
 use dashmap::DashMap;
-use timedmap::TimedMap;
-use anyhow::anyhow;
 use serde_json::Value;
-use reqwest;
-use base64;
 
 
-use ow_common::{ActionCapabilities, WasmAction, WasmRuntime};
+use ow_common::{engine, util, ActionCapabilities, ActivationContext, ActivationFailure, ActivationOutcome, Classify, ContainerInfo, RuntimeError, WasmAction, WasmRuntime};
+use ow_common::abi::{self, AbiKind, Blobs, Guest, GuestAbi, GuestIo, GuestResult};
+use ow_common::artifact;
+use ow_common::cache::{Cache, CacheInfo, INSTANCE_CACHE};
+use ow_common::engine::EngineOptions;
+use ow_common::limits::GuestLimits;
+use ow_common::logs::GuestLogs;
+use ow_common::metrics::{metrics, DownloadSource};
+use ow_common::nn::{self, ContainerGraphs};
+use ow_common::wasi::{self, NetPolicy};
 
 use wasmtime::{Engine, Store};
-use wasmtime::component::{Linker, Component, InstancePre};
+use wasmtime::component::{Linker, InstancePre};
 use wasmtime_wasi::{WasiCtx, WasiView, WasiCtxBuilder, ResourceTable};
+use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView, HttpResult};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{default_send_request, HostFutureIncomingResponse, OutgoingRequestConfig};
 
+use wasmtime_wasi_nn::Registry;
 use wasmtime_wasi_nn::wit::{WasiNnCtx, WasiNnView};
+use tracing::{debug, error, warn};
 
 
 
@@ -21,27 +33,46 @@ use wasmtime_wasi_nn::wit::{WasiNnCtx, WasiNnView};
 #[derive(Clone)]
 pub struct Wasmtime {
     pub engine: Engine,
+    pub options: EngineOptions,
     pub instance_pres: Arc<DashMap<String, WasmAction< InstancePre<MyState> >>>,
-    pub instance_pre_cache: Arc<TimedMap<u64, InstancePre<MyState>>>,
+    pub instance_pre_cache: Arc<Cache<(u64, bool), InstancePre<MyState>>>,
+    pub graphs: ContainerGraphs,
+    /// Downloads models and images, bounded by the download timeout of the options.
+    pub client: reqwest::blocking::Client,
 }
 
-impl Default for Wasmtime {
-    fn default() -> Self {
+impl Wasmtime {
+    pub fn new(options: EngineOptions) -> Self {
         Self {
-            engine: Engine::default(),
+            engine: engine::new_engine(&options),
             instance_pres: Arc::new(DashMap::new()),
-            instance_pre_cache: Arc::new(TimedMap::new()),
+            instance_pre_cache: Arc::new(Cache::new(options.instance_cache.clone())),
+            graphs: ContainerGraphs::new(options.max_container_graphs),
+            client: reqwest::blocking::Client::builder()
+                .timeout(options.download_timeout)
+                .build()
+                .expect("Failed to create the HTTP client"),
+            options,
         }
     }
 }
 
-const CACHE_TTL: Duration = Duration::from_secs(60);
+/// A runtime with the default options. The executor constructs its runtimes with the options
+/// resolved from its settings instead.
+impl Default for Wasmtime {
+    fn default() -> Self {
+        Self::new(EngineOptions::default())
+    }
+}
 
 
 pub struct MyState {
     ctx: WasiCtx,
     wasi_nn: WasiNnCtx,
     table: ResourceTable,
+    http: WasiHttpCtx,
+    net: NetPolicy,
+    limits: GuestLimits,
 }
 
 impl WasiView for MyState {
@@ -54,12 +85,33 @@ impl WasiView for MyState {
     }
 }
 
-impl MyState {
-    fn wasi_nn(&mut self) -> &mut WasiNnCtx {
-        &mut self.wasi_nn
+impl WasiHttpView for MyState {
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.http
     }
-}
 
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn send_request(
+        &mut self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        let host = request.uri().host().unwrap_or_default();
+        let port = request
+            .uri()
+            .port_u16()
+            .unwrap_or(if config.use_tls { 443 } else { 80 });
+
+        if !self.net.allows_host(host, port) {
+            return Err(ErrorCode::HttpRequestDenied.into());
+        }
+
+        Ok(default_send_request(request, config))
+    }
+}
 
 
 impl WasmRuntime for Wasmtime {
@@ -67,42 +119,46 @@ impl WasmRuntime for Wasmtime {
         &self,
         container_id: String,
         capabilities: ActionCapabilities,
+        env: HashMap<String, String>,
         module: Vec<u8>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RuntimeError> {
+
+        let abi = AbiKind::of(&capabilities, true)?;
+        wasi::guest_dir(&self.options, &capabilities)?;
 
-        let module_hash = fasthash::metro::hash64(&module); 
+        // Actions with and without network access are linked differently
+        let net_access = capabilities.net_access.unwrap_or(false);
+        let module_size = module.len();
+        let cache_key = (fasthash::metro::hash64(&module), net_access);
         
         // Check if the preinstance of the module is already in the cache
-        let instance_pre = if let Some(pre) = self.instance_pre_cache.get(&module_hash) {
-            self.instance_pre_cache.refresh(&module_hash, CACHE_TTL);
-            println!("Module found in cache. Using cached module...");
-            pre.clone()
+        let instance_pre = if let Some(pre) = self.instance_pre_cache.get(&cache_key) {
+            debug!("Module found in cache. Using cached module...");
+            pre
         } else {
-            println!("Module not found in cache. Preinstantiating module...");
-            // deserialize could fail due to https://docs.wasmtime.dev/api/wasmtime/struct.Module.html#method.deserialize Unsafety
-            // module must've been precompiled with a matching version of wasmtime
-            let module = unsafe { match Component::deserialize(&self.engine, &module) {
-                                    Ok(module) => module,
-                                    Err(e) => {
-                                        println!("\x1b[31mError deserializing module: {}\x1b[0m", e);
-                                        return Err(anyhow!("Error deserializing module"));
-                                    }
-                                }};
+            debug!("Module not found in cache. Preinstantiating module...");
+            let module = match artifact::load_component(&self.engine, &self.options, &module) {
+                Ok(module) => module,
+                Err(e) => {
+                    error!("Error loading component: {:#}", e);
+                    return Err(e.into());
+                }
+            };
 
             // Add WASI to the linker
             let mut linker = Linker::<MyState>::new(&self.engine);
-            link_host_functions(&mut linker)?;
+            link_host_functions(&mut linker, net_access).internal_error()?;
 
-            let instance_pre = linker.instantiate_pre(&module)?;
+            let instance_pre = linker.instantiate_pre(&module).developer_error()?;
 
-            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), CACHE_TTL);
+            self.instance_pre_cache.insert(cache_key, instance_pre.clone(), module_size);
             instance_pre
         };
 
-        let action = WasmAction {
-            module: instance_pre,
-            capabilities,
-        };
+        // Load the models named by the annotations into the wasi-nn registry of the container
+        self.graphs.preload(&container_id, &capabilities, |url| download(&self.client, url))?;
+
+        let action = WasmAction::new(instance_pre, capabilities, abi, env, cache_key.0);
 
         self.instance_pres.insert(container_id.clone(), action);
 
@@ -113,132 +169,227 @@ impl WasmRuntime for Wasmtime {
     fn run(
         &self,
         container_id: &str,
-        mut parameters: serde_json::Value,
-    ) -> Result<Result<serde_json::Value, serde_json::Value>, anyhow::Error> {
+        context: ActivationContext,
+    ) -> Result<ActivationOutcome, ActivationFailure> {
 
         let wasm_action = self
             .instance_pres
             .get(container_id)
-            .ok_or_else(|| anyhow!(format!("No action named {}", container_id)))?;
+            .ok_or_else(|| RuntimeError::internal(format!("No action named {}", container_id)))?;
+        wasm_action.touch();
         let instance_pre = &wasm_action.module;
+        let env = wasi::guest_env(&wasm_action.env, &context);
+        let mut parameters = context.value;
+
+        // Manage parameter passing, with blobs for the components that take them
+        let mut blobs = Blobs::new(abi::component_takes_blobs(&self.engine, instance_pre.component()));
+        if wasm_action.capabilities.nn_load_by_name.unwrap_or(false) {
+            // The guest loads the model by its URL from the wasi-nn registry of the container
+            if let Some(model_url) = parameters.get("model") {
+                let model_url = model_url.as_str().ok_or_else(|| RuntimeError::developer("From embedder: 'model' is not a string"))?;
+                let encoding = nn::graph_encoding(&wasm_action.capabilities)?;
+                self.graphs.load(container_id, model_url, || {
+                    nn::load_graph(&download(&self.client, model_url)?, encoding)
+                })?;
+            }
+        } else {
+            debug!("Replacing model URL with actual data...");
+            replace_model_url(&mut parameters, &self.client, &mut blobs)?;
+        }
+        debug!("Replacing image URLs with actual data...");
+        replace_image_urls(&mut parameters, &self.client, &mut blobs)?;
+
+        let abi = wasm_action.abi.abi::<MyState>();
+
+        let limits = GuestLimits::new(&self.options, &wasm_action.capabilities)?;
+        let logs = GuestLogs::new(&self.options);
+        let mut io = abi.io(&parameters, &logs);
+        io.set_blobs(blobs.into_vec());
+        let registry = self.graphs.registry(container_id);
+        let mut store = create_store(&self.engine, &self.options, &wasm_action.capabilities, &env, &io, registry, limits).internal_error()?;
+        engine::set_deadline(&mut store, context.deadline);
+        let fuel = engine::set_fuel(&mut store, &self.options, &wasm_action.capabilities).internal_error()?;
+
+        let result = execute(instance_pre, &mut store, abi, &parameters, &io);
+        let fuel_consumed = engine::fuel_consumed(&store, fuel);
+        let logs = logs.finish(&context.activation_id);
+
+        // Failed activations keep their logs, since that is when they are needed the most
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => return Err(ActivationFailure::new(err, fuel_consumed, logs)),
+        };
 
-        let mut store = create_store(&self.engine);
-
-        let instance = instance_pre.instantiate(&mut store).unwrap();
-
-        // Manage parameter passing
-        println!("Replacing model URL with actual data...");
-        replace_model_url(&mut parameters)?;
-        println!("Replacing image URLs with actual data...");
-        replace_image_urls(&mut parameters)?;
-
-        let input = serde_json::to_string(&parameters)?;
-        let mut output = [wasmtime::component::Val::String("".into())];
+        Ok(ActivationOutcome {
+            result,
+            fuel_consumed,
+            logs,
+        })
+    }
 
-        // Call the `func-wrapper` function
-        println!("Calling func-wrapper function...");
-        let func = instance.get_func(&mut store, "func-wrapper").expect("func-wrapper export not found");
-        func.call(&mut store, &[wasmtime::component::Val::String(input.into())], &mut output)?;
+    fn destroy(&self, container_id: &str) {
+        if self.instance_pres.remove(container_id).is_none() {
+            warn!("No container with id {} existed.", container_id);
+        }
+        self.graphs.remove(container_id);
+    }
 
-        // Manage output
-        let response = match &output[0] {
-            wasmtime::component::Val::String(s) => serde_json::from_str(s).unwrap(),
-            _ => serde_json::Value::Null,
-        };
+    fn containers(&self) -> Vec<ContainerInfo> {
+        self.instance_pres
+            .iter()
+            .map(|entry| entry.value().info(entry.key()))
+            .collect()
+    }
 
-        Ok(Ok(response))
+    fn caches(&self) -> Vec<CacheInfo> {
+        vec![self.instance_pre_cache.info(INSTANCE_CACHE, |(hash, net_access)| {
+            let suffix = if *net_access { " net_access" } else { "" };
+            format!("{}{}", util::format_hash(*hash), suffix)
+        })]
     }
 
-    fn destroy(&self, container_id: &str) {
-        if let None = self.instance_pres.remove(container_id) {
-            println!("No container with id {} existed.", container_id);
+    fn evict(&self, cache: &str, key: &str) -> usize {
+        match cache {
+            INSTANCE_CACHE => {
+                // Both the entries with and without network access
+                self.instance_pre_cache.remove_where(|(hash, _)| util::format_hash(*hash) == key)
+            }
+            _ => 0,
         }
     }
 }
 
 
-fn create_store(
-    engine: &Engine,
-) -> Store<MyState> {
+fn execute(
+    instance_pre: &InstancePre<MyState>,
+    store: &mut Store<MyState>,
+    abi: &dyn GuestAbi<MyState>,
+    parameters: &serde_json::Value,
+    io: &GuestIo,
+) -> Result<GuestResult, anyhow::Error> {
+
+    let instance = instance_pre.instantiate(&mut *store).developer_error()?;
+
+    abi.invoke(Guest::Component(&instance), store, parameters, io)
+}
 
-    let wasi = WasiCtxBuilder::new()
-        .inherit_stdio()
-        .inherit_stderr()
-        .build();
 
-    let (backends, registry) = wasmtime_wasi_nn::preload(&[]).unwrap();
-    let wasi_nn = WasiNnCtx::new(backends, registry);
+fn create_store(
+    engine: &Engine,
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+    env: &[(String, String)],
+    io: &GuestIo,
+    registry: Registry,
+    limits: GuestLimits,
+) -> Result<Store<MyState>, anyhow::Error> {
+
+    let mut builder = WasiCtxBuilder::new();
+    builder.envs(env);
+    io.attach(&mut builder);
+    wasi::preopen_dir(&mut builder, options, capabilities)?;
+    wasi::allow_network(&mut builder, capabilities);
+    let wasi = builder.build();
+
+    let wasi_nn = WasiNnCtx::new(wasmtime_wasi_nn::backend::list(), registry);
 
     let wasi_state = MyState { 
         ctx: wasi, 
         table: ResourceTable::new(),
-        wasi_nn: wasi_nn,
+        http: WasiHttpCtx::new(),
+        net: NetPolicy::new(capabilities),
+        wasi_nn,
+        limits,
     };
 
-    Store::new(engine, wasi_state)
+    let mut store = Store::new(engine, wasi_state);
+    store.limiter(|state| &mut state.limits);
+    Ok(store)
 }
 
 
 fn link_host_functions(
-    linker: &mut Linker<MyState>
+    linker: &mut Linker<MyState>,
+    net_access: bool,
 ) -> Result<(), anyhow::Error> {
-    wasmtime_wasi::add_to_linker_sync(linker)?;
+    wasi::add_to_linker_sync(linker, net_access)?;
+    if net_access {
+        wasmtime_wasi_http::add_only_http_to_linker_sync(linker)?;
+    }
     wasmtime_wasi_nn::wit::add_to_linker(linker, |state: &mut MyState| WasiNnView::new(&mut state.table, &mut state.wasi_nn))?;
     Ok(())
 }
 
 
 
+// Downloads `url` with the client of the runtime, failing on error statuses
+fn download(client: &reqwest::blocking::Client, url: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes = client
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .internal_error()?;
+    metrics().downloaded(DownloadSource::Http, bytes.len());
+    Ok(bytes.to_vec())
+}
+
 // This just replaces the 'model' URL with the actual model bytes (always a single model)
+#[tracing::instrument(skip_all)]
 fn replace_model_url(
-    parameters: &mut Value
+    parameters: &mut Value,
+    client: &reqwest::blocking::Client,
+    blobs: &mut Blobs,
 ) -> anyhow::Result<()> {
     if let Some(model_value) = parameters.get_mut("model") {
         if let Some(model_url) = model_value.as_str() {
-            let model_bytes = reqwest::blocking::get(model_url)?.bytes()?.to_vec();
-            *model_value = Value::String(base64::encode(&model_bytes));
+            let model_bytes = download(client, model_url)?;
+            *model_value = blobs.blob("model", model_bytes);
         } else {
-            return Err(anyhow!("From embedder: 'model' is not a string"));
+            return Err(RuntimeError::developer("From embedder: 'model' is not a string").into());
         }
     } else {
-        return Err(anyhow!("From embedder: 'model' not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'model' not found in JSON").into());
     }
     Ok(())
 }
 
 
 
+#[tracing::instrument(skip_all)]
 fn replace_image_urls(
-    parameters: &mut Value
+    parameters: &mut Value,
+    client: &reqwest::blocking::Client,
+    blobs: &mut Blobs,
 ) -> anyhow::Result<()> {
     if let Some(image_value) = parameters.get_mut("image") {
         match image_value {
             // Caso: 'image' es una cadena
             Value::String(image) => {
                 let image_url = image.as_str(); // Tomamos una referencia inmutable
-                let image_bytes = reqwest::blocking::get(image_url)?.bytes()?.to_vec();
-                *image_value = Value::String(base64::encode(&image_bytes));
+                let image_bytes = download(client, image_url)?;
+                *image_value = blobs.image(image_bytes);
             }
             // Caso: 'image' es una lista de cadenas
             Value::Array(images) => {
                 let mut encoded_images = Vec::new();
                 for image in images.iter() {
                     if let Some(image_url) = image.as_str() {
-                        let image_bytes = reqwest::blocking::get(image_url)?.bytes()?.to_vec();
-                        encoded_images.push(Value::String(base64::encode(&image_bytes)));
+                        let image_bytes = download(client, image_url)?;
+                        encoded_images.push(blobs.image(image_bytes));
                     } else {
-                        return Err(anyhow!("From embedder: 'image' list contains a non-string value"));
+                        return Err(RuntimeError::developer("From embedder: 'image' list contains a non-string value").into());
                     }
                 }
                 *image_value = Value::Array(encoded_images);
             }
             // Caso: 'image' no es ni una cadena ni una lista
             _ => {
-                return Err(anyhow!("From embedder: 'image' is not a string or a list of strings"));
+                return Err(RuntimeError::developer("From embedder: 'image' is not a string or a list of strings").into());
             }
         }
     } else {
-        return Err(anyhow!("From embedder: 'image' not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'image' not found in JSON").into());
     }
     Ok(())
 }
\ No newline at end of file
@@ -1,54 +1,77 @@
-use std::{sync::Arc, time::Duration, sync::Mutex};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use dashmap::DashMap;
-use timedmap::TimedMap;
-use anyhow::anyhow;
 use serde_json::Value;
-use reqwest;
-use base64;
 
-use ow_common::{ActionCapabilities, WasmAction, WasmRuntime};
-
-use wasmtime::{Engine, Linker, Module, Store, InstancePre};
+use ow_common::{engine, panic_message, util, ActionCapabilities, ActivationContext, ActivationFailure, ActivationOutcome, Classify, ContainerInfo, RuntimeError, WasmAction, WasmRuntime};
+use ow_common::abi::{self, AbiKind, Blobs, Guest, GuestAbi, GuestIo};
+use ow_common::artifact;
+use ow_common::cache::{Cache, CacheInfo, INSTANCE_CACHE, MODEL_CACHE};
+use ow_common::engine::EngineOptions;
+use ow_common::limits::GuestLimits;
+use ow_common::logs::GuestLogs;
+use ow_common::metrics::{metrics, DownloadSource};
+use ow_common::nn::{self, ContainerGraphs};
+use ow_common::wasi;
+
+use wasmtime::{Engine, Linker, Store, InstancePre};
 use wasmtime_wasi::{WasiCtxBuilder};
 use wasmtime_wasi::preview1::{self, WasiP1Ctx};
 use std::collections::HashMap;
+use wasmtime_wasi_nn::Registry;
 use wasmtime_wasi_nn::witx::WasiNnCtx;
 //use wasmtime_wasi_nn::backend::pytorch::PytorchBackend;
 use std::time::Instant;
-use rayon::prelude::*;
 
 use aws_sdk_s3::Client;
 use aws_config::meta::region::RegionProviderChain;
-use base64::encode;
+use aws_config::{BehaviorVersion, Region};
 use tokio::runtime::Runtime;
-use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, info_span, warn, Span};
 
 #[derive(Clone)]
 pub struct Wasmtime {
     pub engine: Engine,
+    pub options: EngineOptions,
     pub instance_pres: Arc<DashMap<String, WasmAction< InstancePre<WasmCtx> >>>,
-    pub instance_pre_cache: Arc<TimedMap<u64, InstancePre<WasmCtx>>>,
-    pub model_cache: Arc<TimedMap<String, Vec<u8>>>,
+    pub instance_pre_cache: Arc<Cache<u64, InstancePre<WasmCtx>>>,
+    pub model_cache: Arc<Cache<String, Arc<[u8]>>>,
+    pub graphs: ContainerGraphs,
+    /// Downloads models and images, bounded by the download timeout of the options.
+    pub client: reqwest::blocking::Client,
 }
 
-impl Default for Wasmtime {
-    fn default() -> Self {
+impl Wasmtime {
+    pub fn new(options: EngineOptions) -> Self {
         Self {
-            engine: Engine::default(),
+            engine: engine::new_engine(&options),
             instance_pres: Arc::new(DashMap::new()),
-            instance_pre_cache: Arc::new(TimedMap::new()),
-            model_cache: Arc::new(TimedMap::new()),
+            instance_pre_cache: Arc::new(Cache::new(options.instance_cache.clone())),
+            model_cache: Arc::new(Cache::new(options.model_cache.clone())),
+            graphs: ContainerGraphs::new(options.max_container_graphs),
+            client: reqwest::blocking::Client::builder()
+                .timeout(options.download_timeout)
+                .build()
+                .expect("Failed to create the HTTP client"),
+            options,
         }
     }
 }
 
-const CACHE_TTL: Duration = Duration::from_secs(60);
+/// A runtime with the default options. The executor constructs its runtimes with the options
+/// resolved from its settings instead.
+impl Default for Wasmtime {
+    fn default() -> Self {
+        Self::new(EngineOptions::default())
+    }
+}
 
 
 pub struct WasmCtx {
     wasi: WasiP1Ctx,
     wasi_nn: WasiNnCtx,
+    limits: GuestLimits,
 }
 
 impl WasmCtx {
@@ -67,42 +90,45 @@ impl WasmRuntime for Wasmtime {
         &self,
         container_id: String,
         capabilities: ActionCapabilities,
+        env: HashMap<String, String>,
         module: Vec<u8>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RuntimeError> {
 
+        let abi = AbiKind::of(&capabilities, false)?;
+        wasi::guest_dir(&self.options, &capabilities)?;
+        let module_size = module.len();
         let module_hash = fasthash::metro::hash64(&module);
 
         // Check if the preinstance of the module is already in the cache
         let instance_pre = if let Some(pre) = self.instance_pre_cache.get(&module_hash) {
-            self.instance_pre_cache.refresh(&module_hash, CACHE_TTL);
-            println!("Module found in cache. Using cached module...");
-            pre.clone()
+            debug!("Module found in cache. Using cached module...");
+            pre
         } else {
-            println!("Module not found in cache. Preinstantiating module...");
-            // deserialize could fail due to https://docs.wasmtime.dev/api/wasmtime/struct.Module.html#method.deserialize Unsafety
-            // module must've been precompiled with a matching version of wasmtime
-            let module = unsafe { match Module::deserialize(&self.engine, &module) {
-                                    Ok(module) => module,
-                                    Err(e) => {
-                                        println!("\x1b[31mError deserializing module: {}\x1b[0m", e);
-                                        return Err(anyhow!("Error deserializing module"));
-                                    }
-                                }};
+            debug!("Module not found in cache. Preinstantiating module...");
+            let module = match artifact::load_module(&self.engine, &self.options, &module) {
+                Ok(module) => module,
+                Err(e) => {
+                    error!("Error loading module: {:#}", e);
+                    return Err(e.into());
+                }
+            };
 
             // Add WASI to the linker
             let mut linker: Linker<WasmCtx> = Linker::new(&self.engine);
-            link_host_functions(&mut linker)?;
+            link_host_functions(&mut linker).internal_error()?;
 
-            let instance_pre = linker.instantiate_pre(&module)?;
+            let instance_pre = linker.instantiate_pre(&module).developer_error()?;
 
-            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), CACHE_TTL);
+            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), module_size);
             instance_pre
         };
 
-        let action = WasmAction {
-            module: instance_pre,
-            capabilities,
-        };
+        // Load the models named by the annotations into the wasi-nn registry of the container
+        self.graphs.preload(&container_id, &capabilities, |url| {
+            fetch_model(url.to_string(), &self.model_cache, &self.client)
+        })?;
+
+        let action = WasmAction::new(instance_pre, capabilities, abi, env, module_hash);
 
         self.instance_pres.insert(container_id.clone(), action);
 
@@ -112,87 +138,149 @@ impl WasmRuntime for Wasmtime {
     fn run(
         &self,
         container_id: &str,
-        mut parameters: Value,
-    ) -> Result<Result<Value, Value>, anyhow::Error> {
+        context: ActivationContext,
+    ) -> Result<ActivationOutcome, ActivationFailure> {
         let wasm_action = self
             .instance_pres
             .get(container_id)
-            .ok_or_else(|| anyhow!(format!("No action named {}", container_id)))?;
+            .ok_or_else(|| RuntimeError::internal(format!("No action named {}", container_id)))?;
+        wasm_action.touch();
         let instance_pre = &wasm_action.module;
-        handle_replace_images(&mut parameters);
+        let env = wasi::guest_env(&wasm_action.env, &context);
+        let deadline = context.deadline;
+        let mut parameters = context.value;
+        // The images are passed as blobs to modules that take them, and base64-encoded to the others
+        let mut blobs = Blobs::new(abi::module_takes_blobs(wasm_action.abi, instance_pre.module()));
+        handle_replace_images(&mut parameters, &self.options, &self.client, &mut blobs)?;
+        let blobs = blobs.into_vec();
 
         let model_keys = parameters["models"]
             .as_array()
-            .ok_or_else(|| anyhow!("From embedder: 'model' not found in JSON or is not an array"))?;
+            .ok_or_else(|| RuntimeError::developer("From embedder: 'model' not found in JSON or is not an array"))?
+            .iter()
+            .map(|model_key| {
+                model_key
+                    .as_str()
+                    .map(str::to_owned)
+                    .ok_or_else(|| RuntimeError::developer("From embedder: 'model' contains a non-string value"))
+            })
+            .collect::<Result<Vec<String>, _>>()?;
+        let model_keys = Arc::new(model_keys);
+        // Guests that load their models by name find them in the wasi-nn registry of the container
+        let load_by_name = wasm_action.capabilities.nn_load_by_name.unwrap_or(false);
+        let encoding = nn::graph_encoding(&wasm_action.capabilities)?;
+        let next_model = Arc::new(AtomicUsize::new(0));
         let results = Arc::new(Mutex::new(Value::Object(serde_json::Map::new())));
+        let total_fuel = Arc::new(AtomicU64::new(0));
+        let logs = GuestLogs::new(&self.options);
 
         let start_functions_time = Instant::now();
         let mut handles = vec![];
 
-        for model_key in model_keys {
-            let model_key = model_key
-                .as_str()
-                .ok_or_else(|| anyhow!("From embedder: 'model' contains a non-string value"))?
-                .to_string();
+        // The models run on at most `max_model_threads` threads, each taking the next model
+        // when it is done with one, so that an activation with many models does not oversubscribe
+        // the CPU
+        let threads = self.options.max_model_threads.clamp(1, model_keys.len().max(1));
+        // Threads do not inherit the current span, so their spans are children of the activation span
+        let activation_span = Span::current();
+
+        for _ in 0..threads {
+            let model_keys = Arc::clone(&model_keys);
+            let next_model = Arc::clone(&next_model);
             let parameters = parameters.clone();
             let results = Arc::clone(&results);
             let instance_pre = instance_pre.clone();
             let engine = self.engine.clone();
             let model_cache = self.model_cache.clone();
+            let client = self.client.clone();
+            let graphs = self.graphs.clone();
+            let container_id = container_id.to_owned();
+            let options = self.options.clone();
+            let capabilities = wasm_action.capabilities.clone();
+            let abi = wasm_action.abi;
+            let blobs = blobs.clone();
+            let total_fuel = Arc::clone(&total_fuel);
+            let env = env.clone();
+            let logs = logs.clone();
+            let activation_span = activation_span.clone();
 
             let handle = std::thread::spawn(move || -> Result<(), anyhow::Error> {
-                let thread_start = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
-                let start_time = Instant::now();
-
-                let mut store = create_store(&engine);
-
-                let instance = instance_pre.instantiate(&mut store).unwrap();
-
-                // Write the input to the WASM memory
-                pass_input(&instance, &mut store, &parameters)?;
-
-                let start_pass_model_time = Instant::now();
-                let pass_model_start = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
-                pass_model(&instance, &mut store, model_key.clone(), &model_cache)?;
-                let pass_model_end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
-                let pass_model_duration = start_pass_model_time.elapsed().as_secs_f64();
-
-                // Call the _start function
-                let main = instance.get_typed_func::<(), ()>(&mut store, "_start").unwrap();
-                main.call(&mut store, ())?;
-
-                // Retrieve the result from the WASM memory
-                let mut result = retrieve_result(&instance, &mut store)?;
-
-                let thread_end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
-
-                // Add to the metrics the time taken to process the model
-                let duration = start_time.elapsed().as_secs_f64();
-
-                // Add timing metrics
-                result["metrics"]["func_time"] =
-                    serde_json::Value::Number(serde_json::Number::from_f64(duration).unwrap());
-                result["metrics"]["pass_model_time"] =
-                    serde_json::Value::Number(serde_json::Number::from_f64(pass_model_duration).unwrap());
-                result["metrics"]["thread_start"] =
-                    serde_json::Value::Number(serde_json::Number::from_f64(thread_start).unwrap());
-                result["metrics"]["thread_end"] =
-                    serde_json::Value::Number(serde_json::Number::from_f64(thread_end).unwrap());
-                result["metrics"]["pass_model_start"] =
-                    serde_json::Value::Number(serde_json::Number::from_f64(pass_model_start).unwrap());
-                result["metrics"]["pass_model_end"] =
-                    serde_json::Value::Number(serde_json::Number::from_f64(pass_model_end).unwrap());
-
-
-
-                println!("Model {} returned: {}", model_key, result);
-
-                // Store the result
-                let mut results_lock = results.lock().unwrap();
-                results_lock
-                    .as_object_mut()
-                    .unwrap()
-                    .insert(model_key, result);
+                while let Some(model_key) = model_keys.get(next_model.fetch_add(1, Ordering::Relaxed)) {
+                    let model_key = model_key.clone();
+                    let span = info_span!(parent: &activation_span, "model", model = %model_key);
+                    let _enter = span.enter();
+                    let thread_start = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+                    let start_time = Instant::now();
+
+                    // The model is loaded once per container, and the guest gets its name in the
+                    // 'model' parameter instead of its bytes
+                    let mut load_graph_time = None;
+                    let model_parameters;
+                    let parameters = if load_by_name {
+                        let load_start = Instant::now();
+                        graphs.load(&container_id, &model_key, || {
+                            let model = fetch_model(model_key.clone(), &model_cache, &client)?;
+                            nn::load_graph(&model, encoding)
+                        })?;
+                        load_graph_time = Some(load_start.elapsed().as_secs_f64());
+
+                        model_parameters = {
+                            let mut parameters = parameters.clone();
+                            parameters["model"] = Value::String(model_key.clone());
+                            parameters
+                        };
+                        &model_parameters
+                    } else {
+                        &parameters
+                    };
+
+                    let guest_abi = abi.abi::<WasmCtx>();
+                    let limits = GuestLimits::new(&options, &capabilities)?;
+                    let mut io = guest_abi.io(parameters, &logs);
+                    io.set_blobs(blobs.clone());
+                    let registry = graphs.registry(&container_id);
+                    let mut store = create_store(&engine, &options, &capabilities, &env, &io, registry, limits).internal_error()?;
+                    engine::set_deadline(&mut store, deadline);
+                    let fuel = engine::set_fuel(&mut store, &options, &capabilities).internal_error()?;
+
+                    let result = execute(
+                        &instance_pre,
+                        &mut store,
+                        guest_abi,
+                        parameters,
+                        &io,
+                        !load_by_name,
+                        &model_key,
+                        &model_cache,
+                        &client,
+                    );
+                    total_fuel.fetch_add(engine::fuel_consumed(&store, fuel).unwrap_or(0), Ordering::Relaxed);
+                    let mut result = result?;
+
+                    let thread_end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+
+                    // Add to the metrics the time taken to process the model
+                    let duration = start_time.elapsed().as_secs_f64();
+
+                    // Add timing metrics
+                    util::insert_metrics(
+                        &mut result,
+                        "metrics",
+                        [("func_time", duration), ("thread_start", thread_start), ("thread_end", thread_end)],
+                    );
+                    util::insert_metrics(&mut result, "metrics", load_graph_time.map(|time| ("load_graph_time", time)));
+
+
+
+                    debug!("Model {} returned: {}", model_key, result);
+
+                    // Store the result
+                    let mut results_lock = results.lock().unwrap();
+                    results_lock
+                        .as_object_mut()
+                        .unwrap()
+                        .insert(model_key, result);
+                }
 
                 Ok(())
             });
@@ -200,121 +288,163 @@ impl WasmRuntime for Wasmtime {
             handles.push(handle);
         }
 
-        // Wait for all threads to finish
-        for handle in handles {
-            handle.join().map_err(|e| anyhow!("Thread error: {:?}", e))??;
-        }
-
-        let functions_duration = start_functions_time.elapsed().as_secs_f64();
+        // Wait for all threads to finish before reporting the first error, so no guest outlives
+        // the activation
+        let threads_results: Vec<Result<(), anyhow::Error>> = handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|panic| RuntimeError::internal(format!("A model thread panicked: {}", panic_message(&*panic))))?
+            })
+            .collect();
+        let threads_result = threads_results.into_iter().collect::<Result<(), anyhow::Error>>();
+
+        let logs = logs.finish(&context.activation_id);
+        let fuel_consumed = self.options.fuel_metering.then(|| total_fuel.load(Ordering::Relaxed));
+
+        let result = threads_result.map(|()| {
+            let functions_duration = start_functions_time.elapsed().as_secs_f64();
+
+            let mut final_results = Arc::try_unwrap(results)
+                .unwrap_or_else(|_| Mutex::new(Value::Object(serde_json::Map::new())))
+                .into_inner()
+                .unwrap();
+            // Add functions_duration to the metrics
+            final_results["metrics"]["functions_duration"] =
+                serde_json::Value::Number(serde_json::Number::from_f64(functions_duration).unwrap());
+            final_results
+        });
+
+        // The activation fails if any of the models returned an error
+        let result = result.map(|final_results| {
+            let failed = final_results
+                .as_object()
+                .is_some_and(|models| models.values().any(|model| model.get("error").is_some()));
+            if failed {
+                Err(final_results)
+            } else {
+                Ok(final_results)
+            }
+        });
 
-        let mut final_results = Arc::try_unwrap(results)
-            .unwrap_or_else(|_| Mutex::new(Value::Object(serde_json::Map::new())))
-            .into_inner()
-            .unwrap();
-        // Add functions_duration to the metrics
-        final_results["metrics"]["functions_duration"] =
-            serde_json::Value::Number(serde_json::Number::from_f64(functions_duration).unwrap());
+        // Failed activations keep their logs, since that is when they are needed the most
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => return Err(ActivationFailure::new(err, fuel_consumed, logs)),
+        };
 
-        Ok(Ok(final_results))
+        Ok(ActivationOutcome {
+            result,
+            fuel_consumed,
+            logs,
+        })
     }
 
-//     fn run(
-//         &self,
-//         container_id: &str,
-//         mut parameters: Value,
-//     ) -> Result<Result<Value, Value>, anyhow::Error> {
-//         let wasm_action = self
-//             .instance_pres
-//             .get(container_id)
-//             .ok_or_else(|| anyhow!(format!("No action named {}", container_id)))?;
-//         let instance_pre = &wasm_action.module;
-//         handle_replace_images(&mut parameters);
-//
-//         let model_keys = parameters["models"].as_array().ok_or_else(|| anyhow!("From embedder: 'model' not found in JSON or is not an array"))?;
-//         let results = Arc::new(Mutex::new(Value::Object(serde_json::Map::new())));
-//
-//         let start_functions_time = Instant::now();
-//         // Do a parallel iter over the model keys
-//         model_keys.par_iter().try_for_each(|model_key| {
-//             let start_time = Instant::now();
-//
-//             let model_key_str = model_key.as_str().ok_or_else(|| anyhow!("From embedder: 'model' contains a non-string value"))?;
-//
-//             let mut store = create_store(&self.engine);
-//
-//             // Replace the image URLs with their base64-encoded contents (if needed)
-//
-//             let instance = instance_pre.instantiate(&mut store).unwrap();
-//
-//             // Write the input to the WASM memory
-//             pass_input(&instance, &mut store, &parameters)?;
-//
-//             let start_pass_model_time = Instant::now();
-//             pass_model(&instance, &mut store, model_key_str.to_string(), &self.model_cache)?;
-//             let pass_model_duration = start_pass_model_time.elapsed().as_secs_f64();
-//
-//             // Call the _start function
-//             let main = instance.get_typed_func::<(), ()>(&mut store, "_start").unwrap();
-//             main.call(&mut store, ())?;
-//
-//             // Retrieve the result from the WASM memory
-//             let mut result = retrieve_result(&instance, &mut store)?;
-//
-//             // Add to the metrics the time taken to process the model
-//             let duration = start_time.elapsed().as_secs_f64();
-//
-//             // result is a json, add func_time to it
-//             result["metrics"]["func_time"] = serde_json::Value::Number(serde_json::Number::from_f64(duration).unwrap());
-//             result["metrics"]["pass_model_time"] = serde_json::Value::Number(serde_json::Number::from_f64(pass_model_duration).unwrap());
-//             println!("Model {} returned: {}", model_key_str, result);
-//             let mut results_lock = results.lock().unwrap();
-//             results_lock.as_object_mut().unwrap().insert(model_key_str.to_string(), result);
-//
-//             // Return the result
-//             Ok::<(), anyhow::Error>(())
-//         });
-//         let functions_duration = start_functions_time.elapsed().as_secs_f64();
-//
-//         let mut final_results = Arc::try_unwrap(results)
-//         .unwrap_or_else(|_| Mutex::new(Value::Object(serde_json::Map::new())))
-//         .into_inner()
-//         .unwrap();
-//         // Add functions_duration to the metrics
-//         final_results["metrics"]["functions_duration"] = serde_json::Value::Number(serde_json::Number::from_f64(functions_duration).unwrap());
-//
-//         Ok(Ok(final_results))
-//     }
 
     fn destroy(
         &self,
         container_id: &str
     ) {
-        if let None = self.instance_pres.remove(container_id) {
-            println!("No container with id {} existed.", container_id);
+        if self.instance_pres.remove(container_id).is_none() {
+            warn!("No container with id {} existed.", container_id);
+        }
+        self.graphs.remove(container_id);
+    }
+
+    fn containers(&self) -> Vec<ContainerInfo> {
+        self.instance_pres
+            .iter()
+            .map(|entry| entry.value().info(entry.key()))
+            .collect()
+    }
+
+    fn caches(&self) -> Vec<CacheInfo> {
+        vec![
+            self.instance_pre_cache.info(INSTANCE_CACHE, |hash| util::format_hash(*hash)),
+            self.model_cache.info(MODEL_CACHE, |url| url.clone()),
+        ]
+    }
+
+    fn evict(&self, cache: &str, key: &str) -> usize {
+        match cache {
+            INSTANCE_CACHE => self.instance_pre_cache.remove_where(|hash| util::format_hash(*hash) == key),
+            MODEL_CACHE => self.model_cache.remove(&key.to_owned()).map_or(0, |_| 1),
+            _ => 0,
         }
     }
 
 }
 
 
-fn create_store(
-    engine: &Engine
-) -> Store<WasmCtx> {
-    let wasi = WasiCtxBuilder::new()
-        .inherit_stdio()
-        .inherit_stderr()
-        .build_p1();
+#[allow(clippy::too_many_arguments)]
+fn execute(
+    instance_pre: &InstancePre<WasmCtx>,
+    store: &mut Store<WasmCtx>,
+    abi: &dyn GuestAbi<WasmCtx>,
+    parameters: &Value,
+    io: &GuestIo,
+    pass_model: bool,
+    model_key: &str,
+    model_cache: &Cache<String, Arc<[u8]>>,
+    client: &reqwest::blocking::Client,
+) -> Result<Value, anyhow::Error> {
+
+    let instance = instance_pre.instantiate(&mut *store).developer_error()?;
+
+    // Write the model to the WASM memory, unless the guest loads it by name
+    let mut pass_model_metrics = Vec::new();
+    if pass_model {
+        let start_pass_model_time = Instant::now();
+        let pass_model_start = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+        let model = fetch_model(model_key.to_string(), model_cache, client)?;
+        abi::pass_model(&instance, store, &model)?;
+        let pass_model_end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+        let pass_model_duration = start_pass_model_time.elapsed().as_secs_f64();
+        pass_model_metrics = vec![
+            ("pass_model_time", pass_model_duration),
+            ("pass_model_start", pass_model_start),
+            ("pass_model_end", pass_model_end),
+        ];
+    }
+
+    // Failed models keep their `error` field, which fails the whole activation
+    let mut result = abi
+        .invoke(Guest::Module(&instance), store, parameters, io)?
+        .unwrap_or_else(|error| error);
+
+    util::insert_metrics(&mut result, "metrics", pass_model_metrics);
+
+    Ok(result)
+}
 
-    //let graph = vec![("pytorch".to_string(), "models".to_string())]; // Convert to Vec<(String, String)>
-    let (backends, registry) = wasmtime_wasi_nn::preload(&[]).unwrap();
-    let wasi_nn = WasiNnCtx::new(backends, registry);
+
+fn create_store(
+    engine: &Engine,
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+    env: &[(String, String)],
+    io: &GuestIo,
+    registry: Registry,
+    limits: GuestLimits,
+) -> Result<Store<WasmCtx>, anyhow::Error> {
+    let mut builder = WasiCtxBuilder::new();
+    builder.envs(env);
+    io.attach(&mut builder);
+    wasi::preopen_dir(&mut builder, options, capabilities)?;
+    let wasi = builder.build_p1();
+
+    let wasi_nn = WasiNnCtx::new(wasmtime_wasi_nn::backend::list(), registry);
 
     let wasm_ctx = WasmCtx {
         wasi,
         wasi_nn,
+        limits,
     };
 
-    Store::new(engine, wasm_ctx)
+    let mut store = Store::new(engine, wasm_ctx);
+    store.limiter(|ctx| &mut ctx.limits);
+    Ok(store)
 }
 
 fn link_host_functions(
@@ -325,117 +455,60 @@ fn link_host_functions(
     Ok(())
 }
 
-fn pass_input(
-    instance: &wasmtime::Instance,
-    store: &mut Store<WasmCtx>,
-    parameters: &Value
-) -> Result<(), anyhow::Error> {
-
-    let input = parameters.to_string();
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Obtain the pointer to the input with set_input
-    let set_input = instance
-        .get_typed_func::<u32, u32>(&mut *store, "set_input")
-        .map_err(|_| anyhow::anyhow!("Failed to get set_input"))?;
-    let input_ptr = set_input.call(&mut *store, input.len() as u32)? as usize;
-
-    // Write the input to the WASM memory
-    let content = input.as_bytes();
-    memory.data_mut(&mut *store)[input_ptr..(input_ptr + content.len())].copy_from_slice(content);
-
-    Ok(())
-}
-
-
-fn retrieve_result(
-    instance: &wasmtime::Instance,
-    store: &mut Store<WasmCtx>
-) -> Result<Value, anyhow::Error> {
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Get the length of the result with get_result_len
-    let get_result_len = instance
-        .get_typed_func::<(), u32>(&mut *store, "get_result_len")
-        .map_err(|_| anyhow::anyhow!("Failed to get get_result_len"))?;
-    let length = get_result_len.call(&mut *store, ())? as usize;
-
-    // Get the pointer to the result with get_result
-    let get_result = instance
-        .get_typed_func::<(), u32>(&mut *store, "get_result")
-        .map_err(|_| anyhow::anyhow!("Failed to get get_result"))?;
-    let content_ptr = get_result.call(&mut *store, ())? as usize;
-
-    // Read the result from the WASM memory
-    let content = memory.data(&store)[content_ptr..(content_ptr + length)].to_vec();
-
-
-    let result = String::from_utf8(content)?;
-    let json_result: Value = serde_json::from_str(&result)?;
-
-    Ok(json_result)
-}
-
-
-fn pass_model(
-    instance: &wasmtime::Instance,
-    store: &mut Store<WasmCtx>,
+// Returns the model at `model_key`, downloading it unless it is in the cache. Cached models are
+// shared with the cache rather than copied
+#[tracing::instrument(skip_all)]
+fn fetch_model(
     model_key: String,
-    model_cache: &Arc<TimedMap<String, Vec<u8>>>
-) -> Result<(), anyhow::Error> {
+    model_cache: &Cache<String, Arc<[u8]>>,
+    client: &reqwest::blocking::Client,
+) -> Result<Arc<[u8]>, anyhow::Error> {
 
     // Check if the model is already in the cache
     let model_bytes = if let Some(cached_bytes) = model_cache.get(&model_key) {
-        println!("Model found in cache. Using cached model...");
-        model_cache.refresh(&model_key, CACHE_TTL);
-        cached_bytes.clone()
+        debug!("Model found in cache. Using cached model...");
+        cached_bytes
     } else {
-        let client = reqwest::blocking::Client::builder()
-            .timeout(Duration::new(120, 0))
-            .build()?;
-        println!("Model not found in cache. Downloading model...");
-        let response = client.get(&model_key).send()?.error_for_status()?;
-        let downloaded_bytes = response.bytes()?.to_vec();
-        model_cache.insert(model_key.clone(), downloaded_bytes.clone(), CACHE_TTL);
+        info!(model = %model_key, "Model not found in cache. Downloading model...");
+        let downloaded_bytes: Arc<[u8]> = download(client, &model_key)?.into();
+        let size = downloaded_bytes.len();
+        model_cache.insert(model_key.clone(), downloaded_bytes.clone(), size);
         downloaded_bytes
     };
 
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Obtain the pointer to the model with set_model
-    let set_model = instance
-        .get_typed_func::<u32, u32>(&mut *store, "set_model")
-        .map_err(|_| anyhow::anyhow!("Failed to get set_model"))?;
-    let model_ptr = set_model.call(&mut *store, model_bytes.len() as u32)? as usize;
-
-    // Write the model to the WASM memory
-    memory.data_mut(&mut *store)[model_ptr..(model_ptr + model_bytes.len())].copy_from_slice(&model_bytes);
+    Ok(model_bytes)
+}
 
-    Ok(())
+// Downloads `url` with the client of the runtime, failing on error statuses
+fn download(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let bytes = client
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .internal_error()?;
+    metrics().downloaded(DownloadSource::Http, bytes.len());
+    Ok(bytes.to_vec())
 }
 
 
-fn handle_replace_images(parameters: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+fn handle_replace_images(
+    parameters: &mut Value,
+    options: &EngineOptions,
+    client: &reqwest::blocking::Client,
+    blobs: &mut Blobs,
+) -> anyhow::Result<()> {
     let replace_images = parameters
         .get("replace_images")
         .and_then(|v| v.as_str())
         .unwrap_or("");
 
     match replace_images {
-        "URL" => replace_image_urls_parallel(parameters)?,
-        "S3" => replace_image_urls_s3_parallel(parameters)?,
+        "URL" => replace_image_urls_parallel(parameters, client, blobs)?,
+        "S3" => replace_image_urls_s3_parallel(parameters, &options.s3_region, blobs)?,
         _ => {
             // Handle default case or log a warning if necessary
-            println!("No valid replacement option provided");
+            debug!("No valid replacement option provided");
         },
     }
 
@@ -443,15 +516,18 @@ fn handle_replace_images(parameters: &mut Value) -> Result<(), Box<dyn std::erro
 }
 
 // Unused
+#[allow(dead_code)]
+#[tracing::instrument(skip_all)]
 fn replace_image_urls(
-    parameters: &mut Value
+    parameters: &mut Value,
+    client: &reqwest::blocking::Client,
 ) -> anyhow::Result<()> {
     if let Some(image_value) = parameters.get_mut("image") {
         match image_value {
             // Caso: 'image' es una cadena
             Value::String(image) => {
                 let image_url = image.as_str(); // Tomamos una referencia inmutable
-                let image_bytes = reqwest::blocking::get(image_url)?.bytes()?.to_vec();
+                let image_bytes = download(client, image_url)?;
                 *image_value = Value::String(base64::encode(&image_bytes));
             }
             // Caso: 'image' es una lista de cadenas
@@ -459,21 +535,21 @@ fn replace_image_urls(
                 let mut encoded_images = Vec::new();
                 for image in images.iter() {
                     if let Some(image_url) = image.as_str() {
-                        let image_bytes = reqwest::blocking::get(image_url)?.bytes()?.to_vec();
+                        let image_bytes = download(client, image_url)?;
                         encoded_images.push(Value::String(base64::encode(&image_bytes)));
                     } else {
-                        return Err(anyhow!("From embedder: 'image' list contains a non-string value"));
+                        return Err(RuntimeError::developer("From embedder: 'image' list contains a non-string value").into());
                     }
                 }
                 *image_value = Value::Array(encoded_images);
             }
             // Caso: 'image' no es ni una cadena ni una lista
             _ => {
-                return Err(anyhow!("From embedder: 'image' is not a string or a list of strings"));
+                return Err(RuntimeError::developer("From embedder: 'image' is not a string or a list of strings").into());
             }
         }
     } else {
-        return Err(anyhow!("From embedder: 'image' not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'image' not found in JSON").into());
     }
     Ok(())
 }
@@ -481,137 +557,123 @@ fn replace_image_urls(
 
 fn replace_image_urls_parallel(
     parameters: &mut serde_json::Value,
+    client: &reqwest::blocking::Client,
+    blobs: &mut Blobs,
 ) -> anyhow::Result<()> {
-    if let Some(image_value) = parameters.get_mut("image_urls") {
+    if let Some(image_value) = parameters.get("image_urls") {
         match image_value {
             serde_json::Value::Array(image_urls) => {
-                // Shared results container
-                let encoded_images = Arc::new(Mutex::new(Vec::new()));
                 let mut handles = vec![];
 
-                for image in image_urls.clone() {
+                for image in image_urls.iter() {
                     if let Some(image_url) = image.as_str() {
-                        let encoded_images = Arc::clone(&encoded_images);
                         let image_url = image_url.to_string();
+                        let client = client.clone();
 
                         // Spawn a thread for each image URL
-                        let handle = std::thread::spawn(move || -> anyhow::Result<()> {
-                            println!("Downloading image from URL: {}", image_url);
-                            let image_bytes = reqwest::blocking::get(&image_url)?.bytes()?.to_vec();
-                            println!("Image downloaded. Now encoding...");
-                            let encoded_image = serde_json::Value::String(base64::encode(&image_bytes));
-                            println!("Image downloaded and encoded");
-
-                            // Safely append the encoded image to the results
-                            let mut lock = encoded_images.lock().unwrap();
-                            lock.push(encoded_image);
-                            Ok(())
+                        let handle = std::thread::spawn(move || -> anyhow::Result<Vec<u8>> {
+                            debug!("Downloading image from URL: {}", image_url);
+                            let image_bytes = download(&client, &image_url)?;
+                            debug!("Image downloaded");
+                            Ok(image_bytes)
                         });
 
                         handles.push(handle);
                     } else {
-                        return Err(anyhow::anyhow!("From embedder: 'image_urls' list contains a non-string value"));
+                        return Err(RuntimeError::developer("From embedder: 'image_urls' list contains a non-string value").into());
                     }
                 }
 
-                // Wait for all threads to complete
+                // Wait for all threads to complete, keeping the images in the order of their URLs
+                let mut images = Vec::with_capacity(handles.len());
                 for handle in handles {
-                    handle.join().map_err(|e| anyhow::anyhow!("Thread error: {:?}", e))??;
+                    let image_bytes = handle.join().map_err(|e| RuntimeError::internal(format!("Thread error: {:?}", e)))??;
+                    images.push(blobs.image(image_bytes));
                 }
 
                 // Replace the "image" field in parameters with the results
-                let final_results = Arc::try_unwrap(encoded_images)
-                    .unwrap_or_else(|_| Mutex::new(Vec::new()))
-                    .into_inner()
-                    .unwrap();
-                parameters["image"] = serde_json::Value::Array(final_results);
+                parameters["image"] = serde_json::Value::Array(images);
             }
             _ => {
-                return Err(anyhow::anyhow!("From embedder: 'image_urls' is not a list"));
+                return Err(RuntimeError::developer("From embedder: 'image_urls' is not a list").into());
             }
         }
     } else {
-        return Err(anyhow::anyhow!("From embedder: 'image_urls' key not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'image_urls' key not found in JSON").into());
     }
     Ok(())
 }
 
-fn replace_image_urls_s3_parallel(parameters: &mut Value) -> Result<(), anyhow::Error> {
+fn replace_image_urls_s3_parallel(parameters: &mut Value, region: &str, blobs: &mut Blobs) -> Result<(), anyhow::Error> {
     // Configure the S3 client
-    let runtime = Runtime::new()?;
-    let region_provider = RegionProviderChain::default_provider().or_else("eu-west-1");
-    let config = runtime.block_on(aws_config::from_env().region(region_provider).load());
+    let runtime = Runtime::new().internal_error()?;
+    let region_provider = RegionProviderChain::default_provider().or_else(Region::new(region.to_owned()));
+    let config = runtime.block_on(aws_config::defaults(BehaviorVersion::latest()).region(region_provider).load());
     let client = Client::new(&config);
 
-    if let Some(image_value) = parameters.get_mut("image_uris") {
+    if let Some(image_value) = parameters.get("image_uris") {
         match image_value {
             Value::Array(image_uris) => {
-                // Shared results container
-                let encoded_images = Arc::new(Mutex::new(Vec::new()));
                 let mut handles = vec![];
 
-                for image in image_uris.clone() {
+                for image in image_uris.iter() {
                     if let Some(image_url) = image.as_str() {
                         let client = client.clone();
                         let image_url = image_url.to_string();
-                        let encoded_images = Arc::clone(&encoded_images);
                         let runtime = runtime.handle().clone();
 
                         // Spawn a thread for each S3 URI
-                        let handle = std::thread::spawn(move || -> Result<(), anyhow::Error> {
-                            let encoded_image = runtime.block_on(download_image_s3(&client, &image_url))?;
-                            let mut lock = encoded_images.lock().unwrap();
-                            lock.push(Value::String(encoded_image));
-                            Ok(())
+                        let handle = std::thread::spawn(move || -> Result<Vec<u8>, anyhow::Error> {
+                            runtime.block_on(download_image_s3(&client, &image_url))
                         });
 
                         handles.push(handle);
                     } else {
-                        return Err(anyhow!("From embedder: 'image_uris' list contains a non-string value"));
+                        return Err(RuntimeError::developer("From embedder: 'image_uris' list contains a non-string value").into());
                     }
                 }
 
-                // Wait for all threads to complete
+                // Wait for all threads to complete, keeping the images in the order of their URIs
+                let mut images = Vec::with_capacity(handles.len());
                 for handle in handles {
-                    handle.join().map_err(|e| anyhow!("Thread error: {:?}", e))??;
+                    let image_bytes = handle.join().map_err(|e| RuntimeError::internal(format!("Thread error: {:?}", e)))??;
+                    images.push(blobs.image(image_bytes));
                 }
 
                 // Replace the "image" field in parameters with the results
-                let final_results = Arc::try_unwrap(encoded_images)
-                    .unwrap_or_else(|_| Mutex::new(Vec::new()))
-                    .into_inner()
-                    .unwrap();
-                parameters["image"] = Value::Array(final_results);
+                parameters["image"] = Value::Array(images);
             }
             _ => {
-                return Err(anyhow!("From embedder: 'image_uris' is not a list"));
+                return Err(RuntimeError::developer("From embedder: 'image_uris' is not a list").into());
             }
         }
     } else {
-        return Err(anyhow!("From embedder: 'image_uris' key not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'image_uris' key not found in JSON").into());
     }
 
+
     Ok(())
 }
 
 
 
-async fn download_image_s3(client: &Client, s3_url: &str) -> Result<String, anyhow::Error> {
+async fn download_image_s3(client: &Client, s3_url: &str) -> Result<Vec<u8>, anyhow::Error> {
     // Parse the S3 URL (assumes format s3://bucket/key)
     let parts: Vec<&str> = s3_url.trim_start_matches("s3://").splitn(2, '/').collect();
     if parts.len() != 2 {
-        return Err(anyhow!("Invalid S3 URL format"));
+        return Err(RuntimeError::developer("Invalid S3 URL format").into());
     }
     let bucket = parts[0];
     let key = parts[1];
 
     // Download the object from S3
-    let response = client.get_object().bucket(bucket).key(key).send().await?;
-    let body = response.body.collect().await?;
+    let response = client.get_object().bucket(bucket).key(key).send().await.internal_error()?;
+    let body = response.body.collect().await.internal_error()?;
     let bytes = body.into_bytes();
+    metrics().downloaded(DownloadSource::S3, bytes.len());
 
-    // Encode as Base64 and return
-    Ok(encode(&bytes))
+    // The images are encoded later, for guests that do not take blobs
+    Ok(bytes.into())
 }
 
 
@@ -1,53 +1,76 @@
-use std::{sync::Arc, time::Duration, sync::Mutex};
+use std::sync::Arc;
 use dashmap::DashMap;
-use timedmap::TimedMap;
-use anyhow::anyhow;
 use serde_json::Value;
-use reqwest;
-use base64;
 
-use ow_common::{ActionCapabilities, WasmAction, WasmRuntime};
-
-use wasmtime::{Engine, Linker, Module, Store, InstancePre};
+use ow_common::{engine, util, ActionCapabilities, ActivationContext, ActivationFailure, ActivationOutcome, Classify, ContainerInfo, RuntimeError, WasmAction, WasmRuntime};
+use ow_common::abi::{self, AbiKind, Blobs, Guest, GuestAbi, GuestIo, GuestResult};
+use ow_common::artifact;
+use ow_common::cache::{Cache, CacheInfo, INSTANCE_CACHE, MODEL_CACHE};
+use ow_common::engine::EngineOptions;
+use ow_common::limits::GuestLimits;
+use ow_common::logs::GuestLogs;
+use ow_common::metrics::{metrics, DownloadSource};
+use ow_common::nn::{self, ContainerGraphs};
+use ow_common::wasi;
+
+use wasmtime::{Engine, Linker, Store, InstancePre};
 use wasmtime_wasi::{WasiCtxBuilder};
 use wasmtime_wasi::preview1::{self, WasiP1Ctx};
 use std::collections::HashMap;
+use wasmtime_wasi_nn::Registry;
 use wasmtime_wasi_nn::witx::WasiNnCtx;
 //use wasmtime_wasi_nn::backend::pytorch::PytorchBackend;
 use std::time::Instant;
-use rayon::prelude::*;
 
 use aws_sdk_s3::Client;
 use aws_config::meta::region::RegionProviderChain;
-use base64::encode;
+use aws_config::{BehaviorVersion, Region};
 use tokio::runtime::Runtime;
+use tracing::{debug, error, info, warn};
 
 
 #[derive(Clone)]
 pub struct Wasmtime {
     pub engine: Engine,
+    pub options: EngineOptions,
     pub instance_pres: Arc<DashMap<String, WasmAction< InstancePre<WasmCtx> >>>,
-    pub instance_pre_cache: Arc<TimedMap<u64, InstancePre<WasmCtx>>>,
-    pub model_cache: Arc<TimedMap<String, Vec<u8>>>,
+    pub instance_pre_cache: Arc<Cache<u64, InstancePre<WasmCtx>>>,
+    pub model_cache: Arc<Cache<String, Arc<[u8]>>>,
+    pub graphs: ContainerGraphs,
+    /// Downloads models and images, bounded by the download timeout of the options.
+    pub client: reqwest::blocking::Client,
 }
 
-impl Default for Wasmtime {
-    fn default() -> Self {
+impl Wasmtime {
+    pub fn new(options: EngineOptions) -> Self {
         Self {
-            engine: Engine::default(),
+            engine: engine::new_engine(&options),
             instance_pres: Arc::new(DashMap::new()),
-            instance_pre_cache: Arc::new(TimedMap::new()),
-            model_cache: Arc::new(TimedMap::new()),
+            instance_pre_cache: Arc::new(Cache::new(options.instance_cache.clone())),
+            model_cache: Arc::new(Cache::new(options.model_cache.clone())),
+            graphs: ContainerGraphs::new(options.max_container_graphs),
+            client: reqwest::blocking::Client::builder()
+                .timeout(options.download_timeout)
+                .build()
+                .expect("Failed to create the HTTP client"),
+            options,
         }
     }
 }
 
-const CACHE_TTL: Duration = Duration::from_secs(60);
+/// A runtime with the default options. The executor constructs its runtimes with the options
+/// resolved from its settings instead.
+impl Default for Wasmtime {
+    fn default() -> Self {
+        Self::new(EngineOptions::default())
+    }
+}
 
 
 pub struct WasmCtx {
     wasi: WasiP1Ctx,
     wasi_nn: WasiNnCtx,
+    limits: GuestLimits,
 }
 
 impl WasmCtx {
@@ -66,42 +89,45 @@ impl WasmRuntime for Wasmtime {
         &self,
         container_id: String,
         capabilities: ActionCapabilities,
+        env: HashMap<String, String>,
         module: Vec<u8>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RuntimeError> {
 
+        let abi = AbiKind::of(&capabilities, false)?;
+        wasi::guest_dir(&self.options, &capabilities)?;
+        let module_size = module.len();
         let module_hash = fasthash::metro::hash64(&module);
 
         // Check if the preinstance of the module is already in the cache
         let instance_pre = if let Some(pre) = self.instance_pre_cache.get(&module_hash) {
-            self.instance_pre_cache.refresh(&module_hash, CACHE_TTL);
-            println!("Module found in cache. Using cached module...");
-            pre.clone()
+            debug!("Module found in cache. Using cached module...");
+            pre
         } else {
-            println!("Module not found in cache. Preinstantiating module...");
-            // deserialize could fail due to https://docs.wasmtime.dev/api/wasmtime/struct.Module.html#method.deserialize Unsafety
-            // module must've been precompiled with a matching version of wasmtime
-            let module = unsafe { match Module::deserialize(&self.engine, &module) {
-                                    Ok(module) => module,
-                                    Err(e) => {
-                                        println!("\x1b[31mError deserializing module: {}\x1b[0m", e);
-                                        return Err(anyhow!("Error deserializing module"));
-                                    }
-                                }};
+            debug!("Module not found in cache. Preinstantiating module...");
+            let module = match artifact::load_module(&self.engine, &self.options, &module) {
+                Ok(module) => module,
+                Err(e) => {
+                    error!("Error loading module: {:#}", e);
+                    return Err(e.into());
+                }
+            };
 
             // Add WASI to the linker
             let mut linker: Linker<WasmCtx> = Linker::new(&self.engine);
-            link_host_functions(&mut linker)?;
+            link_host_functions(&mut linker).internal_error()?;
 
-            let instance_pre = linker.instantiate_pre(&module)?;
+            let instance_pre = linker.instantiate_pre(&module).developer_error()?;
 
-            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), CACHE_TTL);
+            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), module_size);
             instance_pre
         };
 
-        let action = WasmAction {
-            module: instance_pre,
-            capabilities,
-        };
+        // Load the models named by the annotations into the wasi-nn registry of the container
+        self.graphs.preload(&container_id, &capabilities, |url| {
+            fetch_model(url, &self.model_cache, &self.client)
+        })?;
+
+        let action = WasmAction::new(instance_pre, capabilities, abi, env, module_hash);
 
         self.instance_pres.insert(container_id.clone(), action);
 
@@ -112,75 +138,174 @@ impl WasmRuntime for Wasmtime {
     fn run(
         &self,
         container_id: &str,
-        mut parameters: Value,
-    ) -> Result<Result<Value, Value>, anyhow::Error> {
+        context: ActivationContext,
+    ) -> Result<ActivationOutcome, ActivationFailure> {
         let mut metrics = HashMap::new();
 
         let wasm_action = self
             .instance_pres
             .get(container_id)
-            .ok_or_else(|| anyhow!(format!("No action named {}", container_id)))?;
+            .ok_or_else(|| RuntimeError::internal(format!("No action named {}", container_id)))?;
+        wasm_action.touch();
         let instance_pre = &wasm_action.module;
+        let env = wasi::guest_env(&wasm_action.env, &context);
+        let mut parameters = context.value;
 
-        let mut store = create_store(&self.engine);
-
-        // Replace the image URLs with their base64-encoded contents (if needed)
+        // Replace the image URLs with their contents (if needed), passed as blobs to modules
+        // that take them and base64-encoded to the others
         let start_time = Instant::now();
-        handle_replace_images(&mut parameters);
+        let mut blobs = Blobs::new(abi::module_takes_blobs(wasm_action.abi, instance_pre.module()));
+        handle_replace_images(&mut parameters, &self.options, &self.client, &mut blobs)?;
         metrics.insert("download_images_time", start_time.elapsed().as_secs_f64());
 
-        let instance = instance_pre.instantiate(&mut store).unwrap();
-
-        // Write the input to the WASM memory
-        pass_input(&instance, &mut store, &parameters)?;
-
-        // Write the model to the WASM memory
-        let start_time = Instant::now();
-        pass_model(&instance, &mut store, &parameters, &self.model_cache)?;
-        metrics.insert("pass_model_time", start_time.elapsed().as_secs_f64());
-
-        // Call the _start function
-        let main = instance.get_typed_func::<(), ()>(&mut store, "_start").unwrap();
-        main.call(&mut store, ())?;
+        // Guests that load their model by name find it in the wasi-nn registry of the container,
+        // so it is loaded on the first activation instead of being copied into every instance.
+        // Without a 'model' parameter, they only use the graphs of the `nn_graphs` annotation.
+        let load_by_name = wasm_action.capabilities.nn_load_by_name.unwrap_or(false);
+        if load_by_name && parameters.get("model").is_some() {
+            let start_time = Instant::now();
+            let model_key = model_key(&parameters)?;
+            let encoding = nn::graph_encoding(&wasm_action.capabilities)?;
+            self.graphs.load(container_id, model_key, || {
+                let model = fetch_model(model_key, &self.model_cache, &self.client)?;
+                nn::load_graph(&model, encoding)
+            })?;
+            metrics.insert("load_graph_time", start_time.elapsed().as_secs_f64());
+        }
 
-        // Retrieve the result from the WASM memory
-        let mut result = retrieve_result(&instance, &mut store)?;
+        let abi = wasm_action.abi.abi::<WasmCtx>();
+
+        let limits = GuestLimits::new(&self.options, &wasm_action.capabilities)?;
+        let logs = GuestLogs::new(&self.options);
+        let mut io = abi.io(&parameters, &logs);
+        io.set_blobs(blobs.into_vec());
+        let registry = self.graphs.registry(container_id);
+        let mut store = create_store(&self.engine, &self.options, &wasm_action.capabilities, &env, &io, registry, limits).internal_error()?;
+        engine::set_deadline(&mut store, context.deadline);
+        let fuel = engine::set_fuel(&mut store, &self.options, &wasm_action.capabilities).internal_error()?;
+
+        let result = execute(
+            instance_pre,
+            &mut store,
+            abi,
+            &parameters,
+            &io,
+            !load_by_name,
+            &self.model_cache,
+            &self.client,
+            &mut metrics,
+        );
+        let fuel_consumed = engine::fuel_consumed(&store, fuel);
+        let logs = logs.finish(&context.activation_id);
 
         // Add executor_metrics to the response
-        result["executor_metrics"] = serde_json::json!(metrics);
-        Ok(Ok(result))
+        let result = result.map(|mut result| {
+            let (Ok(value) | Err(value)) = &mut result;
+            util::insert_metrics(value, "executor_metrics", metrics.iter().map(|(key, time)| (*key, *time)));
+            result
+        });
+
+        // Failed activations keep their logs, since that is when they are needed the most
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => return Err(ActivationFailure::new(err, fuel_consumed, logs)),
+        };
+
+        Ok(ActivationOutcome {
+            result,
+            fuel_consumed,
+            logs,
+        })
     }
 
     fn destroy(
         &self,
         container_id: &str
     ) {
-        if let None = self.instance_pres.remove(container_id) {
-            println!("No container with id {} existed.", container_id);
+        if self.instance_pres.remove(container_id).is_none() {
+            warn!("No container with id {} existed.", container_id);
+        }
+        self.graphs.remove(container_id);
+    }
+
+    fn containers(&self) -> Vec<ContainerInfo> {
+        self.instance_pres
+            .iter()
+            .map(|entry| entry.value().info(entry.key()))
+            .collect()
+    }
+
+    fn caches(&self) -> Vec<CacheInfo> {
+        vec![
+            self.instance_pre_cache.info(INSTANCE_CACHE, |hash| util::format_hash(*hash)),
+            self.model_cache.info(MODEL_CACHE, |url| url.clone()),
+        ]
+    }
+
+    fn evict(&self, cache: &str, key: &str) -> usize {
+        match cache {
+            INSTANCE_CACHE => self.instance_pre_cache.remove_where(|hash| util::format_hash(*hash) == key),
+            MODEL_CACHE => self.model_cache.remove(&key.to_owned()).map_or(0, |_| 1),
+            _ => 0,
         }
     }
 
 }
 
 
-fn create_store(
-    engine: &Engine
-) -> Store<WasmCtx> {
-    let wasi = WasiCtxBuilder::new()
-        .inherit_stdio()
-        .inherit_stderr()
-        .build_p1();
+#[allow(clippy::too_many_arguments)]
+fn execute(
+    instance_pre: &InstancePre<WasmCtx>,
+    store: &mut Store<WasmCtx>,
+    abi: &dyn GuestAbi<WasmCtx>,
+    parameters: &Value,
+    io: &GuestIo,
+    pass_model: bool,
+    model_cache: &Cache<String, Arc<[u8]>>,
+    client: &reqwest::blocking::Client,
+    metrics: &mut HashMap<&'static str, f64>
+) -> Result<GuestResult, anyhow::Error> {
+
+    let instance = instance_pre.instantiate(&mut *store).developer_error()?;
 
-    //let graph = vec![("pytorch".to_string(), "models".to_string())]; // Convert to Vec<(String, String)>
-    let (backends, registry) = wasmtime_wasi_nn::preload(&[]).unwrap();
-    let wasi_nn = WasiNnCtx::new(backends, registry);
+    // Write the model to the WASM memory, unless the guest loads it by name
+    if pass_model {
+        let start_time = Instant::now();
+        let model = fetch_model(model_key(parameters)?, model_cache, client)?;
+        abi::pass_model(&instance, store, &model)?;
+        metrics.insert("pass_model_time", start_time.elapsed().as_secs_f64());
+    }
+
+    abi.invoke(Guest::Module(&instance), store, parameters, io)
+}
+
+
+fn create_store(
+    engine: &Engine,
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+    env: &[(String, String)],
+    io: &GuestIo,
+    registry: Registry,
+    limits: GuestLimits,
+) -> Result<Store<WasmCtx>, anyhow::Error> {
+    let mut builder = WasiCtxBuilder::new();
+    builder.envs(env);
+    io.attach(&mut builder);
+    wasi::preopen_dir(&mut builder, options, capabilities)?;
+    let wasi = builder.build_p1();
+
+    let wasi_nn = WasiNnCtx::new(wasmtime_wasi_nn::backend::list(), registry);
 
     let wasm_ctx = WasmCtx {
         wasi,
         wasi_nn,
+        limits,
     };
 
-    Store::new(engine, wasm_ctx)
+    let mut store = Store::new(engine, wasm_ctx);
+    store.limiter(|ctx| &mut ctx.limits);
+    Ok(store)
 }
 
 fn link_host_functions(
@@ -191,120 +316,70 @@ fn link_host_functions(
     Ok(())
 }
 
-fn pass_input(
-    instance: &wasmtime::Instance,
-    store: &mut Store<WasmCtx>,
-    parameters: &Value
-) -> Result<(), anyhow::Error> {
-
-    let input = parameters.to_string();
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Obtain the pointer to the input with set_input
-    let set_input = instance
-        .get_typed_func::<u32, u32>(&mut *store, "set_input")
-        .map_err(|_| anyhow::anyhow!("Failed to get set_input"))?;
-    let input_ptr = set_input.call(&mut *store, input.len() as u32)? as usize;
-
-    // Write the input to the WASM memory
-    let content = input.as_bytes();
-    memory.data_mut(&mut *store)[input_ptr..(input_ptr + content.len())].copy_from_slice(content);
-
-    Ok(())
+// Returns the 'model' URL of the parameters
+fn model_key(parameters: &Value) -> Result<&str, anyhow::Error> {
+    parameters["model"]
+        .as_str()
+        .ok_or_else(|| RuntimeError::developer("From embedder: 'model' not found in JSON").into())
 }
 
+// Returns the model at `model_key`, downloading it unless it is in the cache. Cached models are
+// shared with the cache rather than copied
+#[tracing::instrument(skip_all)]
+fn fetch_model(
+    model_key: &str,
+    model_cache: &Cache<String, Arc<[u8]>>,
+    client: &reqwest::blocking::Client,
+) -> Result<Arc<[u8]>, anyhow::Error> {
 
-fn retrieve_result(
-    instance: &wasmtime::Instance,
-    store: &mut Store<WasmCtx>
-) -> Result<Value, anyhow::Error> {
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Get the length of the result with get_result_len
-    let get_result_len = instance
-        .get_typed_func::<(), u32>(&mut *store, "get_result_len")
-        .map_err(|_| anyhow::anyhow!("Failed to get get_result_len"))?;
-    let length = get_result_len.call(&mut *store, ())? as usize;
-
-    // Get the pointer to the result with get_result
-    let get_result = instance
-        .get_typed_func::<(), u32>(&mut *store, "get_result")
-        .map_err(|_| anyhow::anyhow!("Failed to get get_result"))?;
-    let content_ptr = get_result.call(&mut *store, ())? as usize;
-
-    // Read the result from the WASM memory
-    let content = memory.data(&store)[content_ptr..(content_ptr + length)].to_vec();
-
-
-    let result = String::from_utf8(content)?;
-    let json_result: Value = serde_json::from_str(&result)?;
-
-    Ok(json_result)
-}
-
-
-fn pass_model(
-    instance: &wasmtime::Instance,
-    store: &mut Store<WasmCtx>,
-    parameters: &Value,
-    model_cache: &Arc<TimedMap<String, Vec<u8>>>
-) -> Result<(), anyhow::Error> {
-
-    let model_key = parameters["model"].as_str().ok_or_else(|| anyhow!("From embedder: 'model' not found in JSON"))?.to_string();
+    let model_key = model_key.to_string();
 
     // Check if the model is already in the cache
     let model_bytes = if let Some(cached_bytes) = model_cache.get(&model_key) {
-        println!("Model found in cache. Using cached model...");
-        model_cache.refresh(&model_key, CACHE_TTL);
-        cached_bytes.clone()
+        debug!("Model found in cache. Using cached model...");
+        cached_bytes
     } else {
-        let client = reqwest::blocking::Client::builder()
-            .timeout(Duration::new(120, 0))
-            .build()?;
-        println!("Model not found in cache. Downloading model...");
-        let response = client.get(&model_key).send()?.error_for_status()?;
-        let downloaded_bytes = response.bytes()?.to_vec();
-        model_cache.insert(model_key.clone(), downloaded_bytes.clone(), CACHE_TTL);
+        info!(model = %model_key, "Model not found in cache. Downloading model...");
+        let downloaded_bytes: Arc<[u8]> = download(client, &model_key)?.into();
+        let size = downloaded_bytes.len();
+        model_cache.insert(model_key.clone(), downloaded_bytes.clone(), size);
         downloaded_bytes
     };
 
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Obtain the pointer to the model with set_model
-    let set_model = instance
-        .get_typed_func::<u32, u32>(&mut *store, "set_model")
-        .map_err(|_| anyhow::anyhow!("Failed to get set_model"))?;
-    let model_ptr = set_model.call(&mut *store, model_bytes.len() as u32)? as usize;
-
-    // Write the model to the WASM memory
-    memory.data_mut(&mut *store)[model_ptr..(model_ptr + model_bytes.len())].copy_from_slice(&model_bytes);
+    Ok(model_bytes)
+}
 
-    Ok(())
+// Downloads `url` with the client of the runtime, failing on error statuses
+fn download(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let bytes = client
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .internal_error()?;
+    metrics().downloaded(DownloadSource::Http, bytes.len());
+    Ok(bytes.to_vec())
 }
 
-fn handle_replace_images(parameters: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+fn handle_replace_images(
+    parameters: &mut Value,
+    options: &EngineOptions,
+    client: &reqwest::blocking::Client,
+    blobs: &mut Blobs,
+) -> anyhow::Result<()> {
     let replace_images = parameters
         .get("replace_images")
         .and_then(|v| v.as_str())
         .unwrap_or("");
 
-    println!("Replacing images with method: {}", replace_images);
+    debug!("Replacing images with method: {}", replace_images);
 
     match replace_images {
-        "URL" => replace_image_urls_parallel(parameters)?,
-        "S3" => replace_image_urls_s3_parallel(parameters)?,
+        "URL" => replace_image_urls_parallel(parameters, client, blobs)?,
+        "S3" => replace_image_urls_s3_parallel(parameters, &options.s3_region, blobs)?,
         _ => {
             // Handle default case or log a warning if necessary
-            println!("No valid replacement option provided");
+            debug!("No valid replacement option provided");
         },
     }
 
@@ -313,109 +388,93 @@ fn handle_replace_images(parameters: &mut Value) -> Result<(), Box<dyn std::erro
 
 fn replace_image_urls_parallel(
     parameters: &mut serde_json::Value,
+    client: &reqwest::blocking::Client,
+    blobs: &mut Blobs,
 ) -> anyhow::Result<()> {
-    if let Some(image_value) = parameters.get_mut("image") {
+    if let Some(image_value) = parameters.get("image") {
         match image_value {
             serde_json::Value::Array(image_urls) => {
-                // Shared results container
-                let encoded_images = Arc::new(Mutex::new(Vec::new()));
                 let mut handles = vec![];
-                // Print number of images
-                for image in image_urls.clone() {
+
+                for image in image_urls.iter() {
                     if let Some(image_url) = image.as_str() {
-                        let encoded_images = Arc::clone(&encoded_images);
                         let image_url = image_url.to_string();
+                        let client = client.clone();
+
                         // Spawn a thread for each image URL
-                        let handle = std::thread::spawn(move || -> anyhow::Result<()> {
-                            let image_bytes = reqwest::blocking::get(&image_url)?.bytes()?.to_vec();
-                            let encoded_image = serde_json::Value::String(base64::encode(&image_bytes));
-                            // Safely append the encoded image to the results
-                            let mut lock = encoded_images.lock().unwrap();
-                            lock.push(encoded_image);
-                            Ok(())
-                        });
+                        let handle = std::thread::spawn(move || download(&client, &image_url));
 
                         handles.push(handle);
                     } else {
-                        return Err(anyhow::anyhow!("From embedder: 'image_urls' list contains a non-string value"));
+                        return Err(RuntimeError::developer("From embedder: 'image_urls' list contains a non-string value").into());
                     }
                 }
 
-                // Wait for all threads to complete
+                // Wait for all threads to complete, keeping the images in the order of their URLs
+                let mut images = Vec::with_capacity(handles.len());
                 for handle in handles {
-                    handle.join().map_err(|e| anyhow::anyhow!("Thread error: {:?}", e))??;
+                    let image_bytes = handle.join().map_err(|e| RuntimeError::internal(format!("Thread error: {:?}", e)))??;
+                    images.push(blobs.image(image_bytes));
                 }
 
                 // Replace the "image" field in parameters with the results
-                let final_results = Arc::try_unwrap(encoded_images)
-                    .unwrap_or_else(|_| Mutex::new(Vec::new()))
-                    .into_inner()
-                    .unwrap();
-                parameters["image"] = serde_json::Value::Array(final_results);
+                parameters["image"] = serde_json::Value::Array(images);
             }
             _ => {
-                return Err(anyhow::anyhow!("From embedder: 'image_urls' is not a list"));
+                return Err(RuntimeError::developer("From embedder: 'image_urls' is not a list").into());
             }
         }
     } else {
-        return Err(anyhow::anyhow!("From embedder: 'image_urls' key not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'image_urls' key not found in JSON").into());
     }
     Ok(())
 }
 
-fn replace_image_urls_s3_parallel(parameters: &mut Value) -> Result<(), anyhow::Error> {
+fn replace_image_urls_s3_parallel(parameters: &mut Value, region: &str, blobs: &mut Blobs) -> Result<(), anyhow::Error> {
     // Configure the S3 client
-    let runtime = Runtime::new()?;
-    let region_provider = RegionProviderChain::default_provider().or_else("eu-west-1");
-    let config = runtime.block_on(aws_config::from_env().region(region_provider).load());
+    let runtime = Runtime::new().internal_error()?;
+    let region_provider = RegionProviderChain::default_provider().or_else(Region::new(region.to_owned()));
+    let config = runtime.block_on(aws_config::defaults(BehaviorVersion::latest()).region(region_provider).load());
     let client = Client::new(&config);
 
-    if let Some(image_value) = parameters.get_mut("image_uris") {
+    if let Some(image_value) = parameters.get("image_uris") {
         match image_value {
             Value::Array(image_uris) => {
-                // Shared results container
-                let encoded_images = Arc::new(Mutex::new(Vec::new()));
                 let mut handles = vec![];
 
-                for image in image_uris.clone() {
+                for image in image_uris.iter() {
                     if let Some(image_url) = image.as_str() {
                         let client = client.clone();
                         let image_url = image_url.to_string();
-                        let encoded_images = Arc::clone(&encoded_images);
                         let runtime = runtime.handle().clone();
 
                         // Spawn a thread for each S3 URI
-                        let handle = std::thread::spawn(move || -> Result<(), anyhow::Error> {
-                            let encoded_image = runtime.block_on(download_image_s3(&client, &image_url))?;
-                            let mut lock = encoded_images.lock().unwrap();
-                            lock.push(Value::String(encoded_image));
-                            Ok(())
+                        let handle = std::thread::spawn(move || -> Result<Vec<u8>, anyhow::Error> {
+                            runtime.block_on(download_image_s3(&client, &image_url))
                         });
 
                         handles.push(handle);
                     } else {
-                        return Err(anyhow!("From embedder: 'image_uris' list contains a non-string value"));
+                        return Err(RuntimeError::developer("From embedder: 'image_uris' list contains a non-string value").into());
                     }
                 }
 
-                // Wait for all threads to complete
+                // Wait for all threads to complete, keeping the images in the order of their URIs
+                let mut images = Vec::with_capacity(handles.len());
                 for handle in handles {
-                    handle.join().map_err(|e| anyhow!("Thread error: {:?}", e))??;
+                    let image_bytes = handle.join().map_err(|e| RuntimeError::internal(format!("Thread error: {:?}", e)))??;
+                    images.push(blobs.image(image_bytes));
                 }
 
                 // Replace the "image" field in parameters with the results
-                let final_results = Arc::try_unwrap(encoded_images)
-                    .unwrap_or_else(|_| Mutex::new(Vec::new()))
-                    .into_inner()
-                    .unwrap();
-                parameters["image"] = Value::Array(final_results);
+                parameters["image"] = Value::Array(images);
             }
             _ => {
-                return Err(anyhow!("From embedder: 'image_uris' is not a list"));
+                return Err(RuntimeError::developer("From embedder: 'image_uris' is not a list").into());
             }
         }
     } else {
-        return Err(anyhow!("From embedder: 'image_uris' key not found in JSON"));
+        return Err(RuntimeError::developer("From embedder: 'image_uris' key not found in JSON").into());
     }
 
 
@@ -423,22 +482,23 @@ fn replace_image_urls_s3_parallel(parameters: &mut Value) -> Result<(), anyhow::
 }
 
 
-async fn download_image_s3(client: &Client, s3_url: &str) -> Result<String, anyhow::Error> {
+async fn download_image_s3(client: &Client, s3_url: &str) -> Result<Vec<u8>, anyhow::Error> {
     // Parse the S3 URL (assumes format s3://bucket/key)
     let parts: Vec<&str> = s3_url.trim_start_matches("s3://").splitn(2, '/').collect();
     if parts.len() != 2 {
-        return Err(anyhow!("Invalid S3 URL format"));
+        return Err(RuntimeError::developer("Invalid S3 URL format").into());
     }
     let bucket = parts[0];
     let key = parts[1];
 
     // Download the object from S3
-    let response = client.get_object().bucket(bucket).key(key).send().await?;
-    let body = response.body.collect().await?;
+    let response = client.get_object().bucket(bucket).key(key).send().await.internal_error()?;
+    let body = response.body.collect().await.internal_error()?;
     let bytes = body.into_bytes();
+    metrics().downloaded(DownloadSource::S3, bytes.len());
 
-    // Encode as Base64 and return
-    Ok(encode(&bytes))
+    // The images are encoded later, for guests that do not take blobs
+    Ok(bytes.into())
 }
 
 
@@ -1,32 +1,52 @@
-use std::{sync::Arc, time::Duration,};
+use std::sync::Arc;
+use std::collections::HashMap;
 use dashmap::DashMap;
-use timedmap::TimedMap;
-use anyhow::anyhow;
-use ow_common::{ActionCapabilities, WasmAction, WasmRuntime};
+use ow_common::{engine, util, ActionCapabilities, Classify, RuntimeError, ActivationContext, ActivationFailure, ActivationOutcome, ContainerInfo, WasmAction, WasmRuntime};
+use ow_common::abi::{AbiKind, Guest, GuestAbi, GuestIo, GuestResult};
+use ow_common::artifact;
+use ow_common::cache::{Cache, CacheInfo, INSTANCE_CACHE};
+use ow_common::engine::EngineOptions;
+use ow_common::limits::GuestLimits;
+use ow_common::logs::GuestLogs;
+use ow_common::wasi;
 
 use wasmtime::*;
-use wasi_common::sync::WasiCtxBuilder;
-use wasi_common::WasiCtx;
+use wasmtime_wasi::WasiCtxBuilder;
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use tracing::{debug, error, warn};
 
 #[derive(Clone)]
 pub struct Wasmtime {
     pub engine: Engine,
-    pub instance_pres: Arc<DashMap<String, WasmAction< InstancePre<WasiCtx> >>>,
-    pub instance_pre_cache: Arc<TimedMap<u64, InstancePre<WasiCtx>>>
+    pub options: EngineOptions,
+    pub instance_pres: Arc<DashMap<String, WasmAction< InstancePre<WasmCtx> >>>,
+    pub instance_pre_cache: Arc<Cache<u64, InstancePre<WasmCtx>>>
 }
 
-impl Default for Wasmtime {
-    fn default() -> Self {
+impl Wasmtime {
+    pub fn new(options: EngineOptions) -> Self {
         Self {
-            engine: Engine::default(),
+            engine: engine::new_engine(&options),
             instance_pres: Arc::new(DashMap::new()),
-            instance_pre_cache: Arc::new(TimedMap::new()),
+            instance_pre_cache: Arc::new(Cache::new(options.instance_cache.clone())),
+            options,
         }
     }
 }
 
-const CACHE_TTL: Duration = Duration::from_secs(60);
+/// A runtime with the default options. The executor constructs its runtimes with the options
+/// resolved from its settings instead.
+impl Default for Wasmtime {
+    fn default() -> Self {
+        Self::new(EngineOptions::default())
+    }
+}
+
 
+pub struct WasmCtx {
+    wasi: WasiP1Ctx,
+    limits: GuestLimits,
+}
 
 
 impl WasmRuntime for Wasmtime {
@@ -34,42 +54,40 @@ impl WasmRuntime for Wasmtime {
         &self,
         container_id: String,
         capabilities: ActionCapabilities,
+        env: HashMap<String, String>,
         module: Vec<u8>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RuntimeError> {
 
+        let abi = AbiKind::of(&capabilities, false)?;
+        wasi::guest_dir(&self.options, &capabilities)?;
+        let module_size = module.len();
         let module_hash = fasthash::metro::hash64(&module); 
         
         // Check if the preinstance of the module is already in the cache
         let instance_pre = if let Some(pre) = self.instance_pre_cache.get(&module_hash) {
-            self.instance_pre_cache.refresh(&module_hash, CACHE_TTL);
-            println!("Module found in cache. Using cached module...");
-            pre.clone()
+            debug!("Module found in cache. Using cached module...");
+            pre
         } else {
-            println!("Module not found in cache. Preinstantiating module...");
-            // deserialize could fail due to https://docs.wasmtime.dev/api/wasmtime/struct.Module.html#method.deserialize Unsafety
-            // module must've been precompiled with a matching version of wasmtime
-            let module = unsafe { match Module::deserialize(&self.engine, &module) {
-                                    Ok(module) => module,
-                                    Err(e) => {
-                                        println!("\x1b[31mError deserializing module: {}\x1b[0m", e);
-                                        return Err(anyhow!("Error deserializing module"));
-                                    }
-                                }};
+            debug!("Module not found in cache. Preinstantiating module...");
+            let module = match artifact::load_module(&self.engine, &self.options, &module) {
+                Ok(module) => module,
+                Err(e) => {
+                    error!("Error loading module: {:#}", e);
+                    return Err(e.into());
+                }
+            };
 
             // Add WASI to the linker
-            let mut linker: wasmtime::Linker<WasiCtx> = Linker::new(&self.engine);
-            link_host_functions(&mut linker)?;
+            let mut linker: wasmtime::Linker<WasmCtx> = Linker::new(&self.engine);
+            link_host_functions(&mut linker).internal_error()?;
 
-            let instance_pre = linker.instantiate_pre(&module)?;
+            let instance_pre = linker.instantiate_pre(&module).developer_error()?;
 
-            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), CACHE_TTL);
+            self.instance_pre_cache.insert(module_hash, instance_pre.clone(), module_size);
             instance_pre
         };
 
-        let action = WasmAction {
-            module: instance_pre,
-            capabilities,
-        };
+        let action = WasmAction::new(instance_pre, capabilities, abi, env, module_hash);
 
         self.instance_pres.insert(container_id.clone(), action);
 
@@ -80,30 +98,42 @@ impl WasmRuntime for Wasmtime {
     fn run(
         &self,
         container_id: &str,
-        parameters: serde_json::Value,
-    ) -> Result<Result<serde_json::Value, serde_json::Value>, anyhow::Error> {
+        context: ActivationContext,
+    ) -> Result<ActivationOutcome, ActivationFailure> {
 
         let wasm_action = self
             .instance_pres
             .get(container_id)
-            .ok_or_else(|| anyhow!(format!("No action named {}", container_id)))?;
+            .ok_or_else(|| RuntimeError::internal(format!("No action named {}", container_id)))?;
+        wasm_action.touch();
         let instance_pre = &wasm_action.module;
+        let env = wasi::guest_env(&wasm_action.env, &context);
+        let parameters = context.value;
+
+        let abi = wasm_action.abi.abi::<WasmCtx>();
+
+        let limits = GuestLimits::new(&self.options, &wasm_action.capabilities)?;
+        let logs = GuestLogs::new(&self.options);
+        let io = abi.io(&parameters, &logs);
+        let mut store = create_store(&self.engine, &self.options, &wasm_action.capabilities, &env, &io, limits).internal_error()?;
+        engine::set_deadline(&mut store, context.deadline);
+        let fuel = engine::set_fuel(&mut store, &self.options, &wasm_action.capabilities).internal_error()?;
+
+        let result = execute(instance_pre, &mut store, abi, &parameters, &io);
+        let fuel_consumed = engine::fuel_consumed(&store, fuel);
+        let logs = logs.finish(&context.activation_id);
+
+        // Failed activations keep their logs, since that is when they are needed the most
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => return Err(ActivationFailure::new(err, fuel_consumed, logs)),
+        };
 
-        let mut store = create_store(&self.engine);
-
-        let instance = instance_pre.instantiate(&mut store).unwrap();
-
-        // Write the input to the WASM memory
-        pass_input(&instance, &mut store, &parameters)?;
-
-        // Call the _start function
-        let main = instance.get_typed_func::<(), ()>(&mut store, "_start").unwrap();
-        main.call(&mut store, ())?;
-
-        // Retrieve the result from the WASM memory
-        let result = retrieve_result(&instance, &mut store)?;
-
-        Ok(Ok(result))
+        Ok(ActivationOutcome {
+            result,
+            fuel_consumed,
+            logs,
+        })
     }
 
 
@@ -111,86 +141,74 @@ impl WasmRuntime for Wasmtime {
         &self, 
         container_id: &str
     ) {
-        if let None = self.instance_pres.remove(container_id) {
-            println!("No container with id {} existed.", container_id);
+        if self.instance_pres.remove(container_id).is_none() {
+            warn!("No container with id {} existed.", container_id);
         }
     }
 
-}
-
+    fn containers(&self) -> Vec<ContainerInfo> {
+        self.instance_pres
+            .iter()
+            .map(|entry| entry.value().info(entry.key()))
+            .collect()
+    }
 
-fn create_store(
-    engine: &Engine
-) -> Store<WasiCtx> {
-    let wasi = WasiCtxBuilder::new()
-        .inherit_stdio()
-        .inherit_stderr()
-        .build();
-
-    Store::new(engine, wasi)
-}
+    fn caches(&self) -> Vec<CacheInfo> {
+        vec![self.instance_pre_cache.info(INSTANCE_CACHE, |hash| util::format_hash(*hash))]
+    }
 
+    fn evict(&self, cache: &str, key: &str) -> usize {
+        match cache {
+            INSTANCE_CACHE => self.instance_pre_cache.remove_where(|hash| util::format_hash(*hash) == key),
+            _ => 0,
+        }
+    }
 
-fn link_host_functions(
-    linker: &mut wasmtime::Linker<WasiCtx>
-) -> Result<(), anyhow::Error> {
-    wasi_common::sync::add_to_linker(linker, |s| s)?;
-    Ok(())
 }
 
 
-fn pass_input(
-    instance: &wasmtime::Instance, 
-    store: &mut Store<WasiCtx>, 
-    parameters: &serde_json::Value
-) -> Result<(), anyhow::Error> {
-
-    let input = parameters.to_string();
-    // Access the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
+fn execute(
+    instance_pre: &InstancePre<WasmCtx>,
+    store: &mut Store<WasmCtx>,
+    abi: &dyn GuestAbi<WasmCtx>,
+    parameters: &serde_json::Value,
+    io: &GuestIo,
+) -> Result<GuestResult, anyhow::Error> {
 
-    // Obtain the pointer to the input with set_input
-    let set_input = instance
-        .get_typed_func::<u32, u32>(&mut *store, "set_input")
-        .map_err(|_| anyhow::anyhow!("Failed to get set_input"))?;
-    let input_ptr = set_input.call(&mut *store, input.len() as u32)? as usize;
+    let instance = instance_pre.instantiate(&mut *store).developer_error()?;
 
-    // Write the input to the WASM memory
-    let content = input.as_bytes();
-    memory.data_mut(&mut *store)[input_ptr..(input_ptr + content.len())].copy_from_slice(content);
-
-    Ok(())
+    abi.invoke(Guest::Module(&instance), store, parameters, io)
 }
 
-fn retrieve_result(
-    instance: &wasmtime::Instance, 
-    store: &mut Store<WasiCtx>
-) -> Result<serde_json::Value> {
 
-    // Acces the WASM memory
-    let memory = instance
-        .get_memory(&mut *store, "memory")
-        .ok_or_else(|| anyhow::anyhow!("Failed to get WASM memory"))?;
-
-    // Obtain the length of the result with get_result_len
-    let get_result_len = instance
-        .get_typed_func::<(), u32>(&mut *store, "get_result_len")
-        .map_err(|_| anyhow::anyhow!("Failed to get get_result_len"))?;
-    let length = get_result_len.call(&mut *store, ())? as usize;
-
-    // Obtain the pointer to the result with get_result
-    let get_result = instance
-        .get_typed_func::<(), u32>(&mut *store, "get_result")
-        .map_err(|_| anyhow::anyhow!("Failed to get get_result"))?;
-    let content_ptr = get_result.call(&mut *store, ())? as usize;
-
-    // Read the result from the WASM memory
-    let content = memory.data(&store)[content_ptr..(content_ptr + length)].to_vec();
-    let result = String::from_utf8(content)?;
+fn create_store(
+    engine: &Engine,
+    options: &EngineOptions,
+    capabilities: &ActionCapabilities,
+    env: &[(String, String)],
+    io: &GuestIo,
+    limits: GuestLimits,
+) -> Result<Store<WasmCtx>, anyhow::Error> {
+    let mut builder = WasiCtxBuilder::new();
+    builder.envs(env);
+    io.attach(&mut builder);
+    wasi::preopen_dir(&mut builder, options, capabilities)?;
+    let wasi = builder.build_p1();
+
+    let wasm_ctx = WasmCtx {
+        wasi,
+        limits,
+    };
+
+    let mut store = Store::new(engine, wasm_ctx);
+    store.limiter(|ctx| &mut ctx.limits);
+    Ok(store)
+}
 
-    let json_result: serde_json::Value = serde_json::from_str(&result)?;
 
-    Ok(json_result)
-}
\ No newline at end of file
+fn link_host_functions(
+    linker: &mut wasmtime::Linker<WasmCtx>
+) -> Result<(), anyhow::Error> {
+    preview1::add_to_linker_sync(linker, |ctx: &mut WasmCtx| &mut ctx.wasi)?;
+    Ok(())
+}